@@ -1,10 +1,15 @@
 use aws_sign_v4::AwsSign;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use chrono::{DateTime, Utc};
 use reqwest::{Client, Response, header::HeaderMap};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, warn};
 use url::Url;
 
-use crate::config::Config;
+use crate::config::{Config, S3Config};
 use crate::error::{Error, Result};
+use crate::metrics;
 
 impl From<tokio::io::Error> for Error {
     fn from(err: tokio::io::Error) -> Self {
@@ -15,75 +20,320 @@ impl From<tokio::io::Error> for Error {
     }
 }
 
-async fn get_file_from_file(path: &str) -> Result<Vec<u8>> {
+/// A file fetched from a backend
+pub struct BackendFile {
+    pub bytes: Vec<u8>,
+    /// The upstream `Last-Modified` time, when the backend provides one
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl BackendFile {
+    async fn from_response(mut resp: Response, config: &Config) -> Result<Self> {
+        // Error pages aren't images, and a missing file may have a fallback
+        let status = resp.status();
+        if matches!(
+            status,
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE
+        ) {
+            return Err(Error::NotFound);
+        }
+        if !status.is_success() {
+            return Err(Error::Upstream(status.as_u16()));
+        }
+
+        let last_modified = resp
+            .headers()
+            .get(reqwest::header::LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_http_date);
+
+        let Some(max) = config.max_source_bytes else {
+            return Ok(Self {
+                bytes: resp.bytes().await?.to_vec(),
+                last_modified,
+            });
+        };
+        if let Some(length) = resp.content_length() {
+            check_source_bytes(length, max)?;
+        }
+        // The advertised length can't be trusted, so stop reading once the body passes the limit
+        let mut bytes = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            bytes.extend_from_slice(&chunk);
+            check_source_bytes(bytes.len() as u64, max)?;
+        }
+        Ok(Self {
+            bytes,
+            last_modified,
+        })
+    }
+}
+
+fn check_source_bytes(length: u64, max: u64) -> Result<()> {
+    if length > max {
+        return Err(Error::SourceTooLarge(format!(
+            "source of {length} bytes exceeds the maximum of {max} bytes"
+        )));
+    }
+    Ok(())
+}
+
+/// Parse an HTTP date such as `Tue, 20 Feb 2024 12:00:00 GMT`
+pub fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// Format a time as an HTTP date
+pub fn format_http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+async fn get_file_from_file(path: &str, config: &Config) -> Result<BackendFile> {
     let full_path: PathBuf = Path::new(&path).canonicalize()?;
-    Ok(tokio::fs::read(&full_path).await?)
+    // Paths are compared after resolving `..` and symlinks so they can't escape the root
+    if let Some(root) = &config.file_root
+        && !full_path.starts_with(Path::new(root).canonicalize()?)
+    {
+        return Err(Error::NotFound);
+    }
+    if let Some(max) = config.max_source_bytes {
+        check_source_bytes(tokio::fs::metadata(&full_path).await?.len(), max)?;
+    }
+    Ok(BackendFile {
+        bytes: tokio::fs::read(&full_path).await?,
+        last_modified: None,
+    })
+}
+
+/// Decode an inline `data:image/...;base64,...` URI
+fn get_file_from_data(uri: &str) -> Result<BackendFile> {
+    let (media_type, data) = uri
+        .strip_prefix("data:")
+        .and_then(|uri| uri.split_once(','))
+        .ok_or(Error::InvalidBackend)?;
+    let media_type = media_type
+        .strip_suffix(";base64")
+        .ok_or(Error::InvalidBackend)?;
+    if !media_type.starts_with("image/") {
+        return Err(Error::InvalidBackend);
+    }
+
+    Ok(BackendFile {
+        bytes: BASE64.decode(data).map_err(|_| Error::InvalidBackend)?,
+        last_modified: None,
+    })
+}
+
+/// Whether a URL's host is permitted by `allowed_hosts`. All hosts are allowed
+/// when no list is configured.
+fn host_allowed(url: &Url, allowed_hosts: Option<&[String]>) -> bool {
+    let Some(allowed_hosts) = allowed_hosts else {
+        return true;
+    };
+    url.host_str().is_some_and(|host| {
+        allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    })
+}
+
+/// Whether an image URL taken from a request, such as a watermark, may be
+/// fetched. It must be a data URI, inside the route's endpoint, or an HTTP URL
+/// on one of `overlay_hosts`, so requests can't reach arbitrary hosts, files or
+/// buckets with the service's credentials.
+pub fn overlay_allowed(url: &Url, endpoint: &str, overlay_hosts: &[String]) -> bool {
+    if url.scheme() == "data" {
+        return true;
+    }
+    if Url::parse(endpoint).is_ok_and(|endpoint| within_endpoint(url, &endpoint)) {
+        return true;
+    }
+    matches!(url.scheme(), "http" | "https")
+        && url.host_str().is_some_and(|host| {
+            overlay_hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host))
+        })
+}
+
+/// Whether a URL is on the endpoint's scheme, host and port, with a path at or
+/// below the endpoint's path. Both URLs are normalized, so `..` segments can't
+/// climb out, and a path without a trailing slash only matches whole segments.
+fn within_endpoint(url: &Url, endpoint: &Url) -> bool {
+    if url.scheme() != endpoint.scheme()
+        || url.host_str() != endpoint.host_str()
+        || url.port_or_known_default() != endpoint.port_or_known_default()
+    {
+        return false;
+    }
+    let (path, prefix) = (url.path(), endpoint.path());
+    if prefix.ends_with('/') {
+        path.starts_with(prefix)
+    } else {
+        path == prefix
+            || path
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
 }
 
-async fn get_file_from_http(url: &str, config: &Config) -> Result<Vec<u8>> {
-    let client = Client::builder()
-        .timeout(std::time::Duration::from_secs(config.read_timeout))
-        .build()?;
-    Ok(send_request(&client, url, HeaderMap::new())
-        .await?
-        .bytes()
-        .await?
-        .to_vec())
+/// Build the HTTP client shared by all backend requests so connections are pooled
+pub fn http_client(config: &Config) -> Result<Client> {
+    // Redirects are checked too, otherwise an allowed host could bounce the fetch elsewhere
+    let allowed_hosts = config.allowed_hosts.clone();
+    let redirect = reqwest::redirect::Policy::custom(move |attempt| {
+        if !host_allowed(attempt.url(), allowed_hosts.as_deref()) {
+            attempt.error("redirect to a host that is not allowed")
+        } else if attempt.previous().len() >= 10 {
+            attempt.error("too many redirects")
+        } else {
+            attempt.follow()
+        }
+    });
+    Ok(Client::builder()
+        .timeout(Duration::from_secs(config.read_timeout))
+        .redirect(redirect)
+        .build()?)
 }
 
-async fn get_file_from_s3(bucket: &str, path: &str, config: &Config) -> Result<Vec<u8>> {
+async fn get_file_from_http(
+    url: &str,
+    client: &Client,
+    config: &Config,
+    headers: HeaderMap,
+) -> Result<BackendFile> {
+    if !host_allowed(&Url::parse(url)?, config.allowed_hosts.as_deref()) {
+        return Err(Error::InvalidBackend);
+    }
+    BackendFile::from_response(send_request(client, url, headers, config).await?, config).await
+}
+
+async fn get_file_from_s3(
+    bucket: &str,
+    path: &str,
+    client: &Client,
+    config: &Config,
+) -> Result<BackendFile> {
     if config.s3.is_none() {
         return Err(Error::InvalidBackend);
     }
     if let Some(s3config) = &config.s3 {
-        let url = format!(
-            "http://{}.s3.{}.amazonaws.com{}",
-            &bucket, &s3config.region, path
-        );
+        let url = s3_url(bucket, path, s3config)?;
         let datetime = chrono::Utc::now();
-        let client = Client::builder()
-            .timeout(std::time::Duration::from_secs(config.read_timeout))
-            .build()?;
         let resp = send_request(
-            &client,
+            client,
             &url,
             generate_sigv4_headers(&datetime, &url, config),
+            config,
         )
         .await?;
         // 403 typically means the file does not exist
         if resp.status() == reqwest::StatusCode::FORBIDDEN {
             return Err(Error::NotFound);
         }
-        return Ok(resp.bytes().await?.to_vec());
+        return BackendFile::from_response(resp, config).await;
     }
 
     Err(Error::InvalidBackend)
 }
 
-pub async fn get_file_from_backend(url: &str, config: &Config) -> Result<Vec<u8>> {
+/// Build the URL for an object, using virtual-hosted-style (`bucket.host/key`)
+/// or path-style (`host/bucket/key`) addressing.
+fn s3_url(bucket: &str, path: &str, s3config: &S3Config) -> Result<String> {
+    let (scheme, authority) = match &s3config.endpoint {
+        Some(endpoint) => {
+            let endpoint = Url::parse(endpoint)?;
+            let host = endpoint.host_str().ok_or(Error::InvalidBackend)?;
+            let authority = match endpoint.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            };
+            (endpoint.scheme().to_string(), authority)
+        }
+        None => (
+            "http".to_string(),
+            format!("s3.{}.amazonaws.com", s3config.region),
+        ),
+    };
+
+    if s3config.path_style {
+        Ok(format!("{}://{}/{}{}", scheme, authority, bucket, path))
+    } else {
+        Ok(format!("{}://{}.{}{}", scheme, bucket, authority, path))
+    }
+}
+
+/// Fetch a file from its backend. `headers` are only sent to HTTP backends.
+pub async fn get_file_from_backend(
+    url: &str,
+    client: &Client,
+    config: &Config,
+    headers: HeaderMap,
+) -> Result<BackendFile> {
     let url = Url::parse(url)?;
     match url.scheme() {
-        "file" => get_file_from_file(url.path()).await,
-        "http" | "https" => get_file_from_http(url.as_str(), config).await,
-        "s3" => get_file_from_s3(url.host_str().unwrap(), url.path(), config).await,
+        "file" => get_file_from_file(url.path(), config).await,
+        "data" => get_file_from_data(url.as_str()),
+        "http" | "https" => get_file_from_http(url.as_str(), client, config, headers).await,
+        "s3" => {
+            let bucket = url.host_str().ok_or(Error::InvalidBackend)?;
+            get_file_from_s3(bucket, url.path(), client, config).await
+        }
         _ => Err(Error::InvalidBackend),
     }
 }
 
-async fn send_request(client: &Client, url: &str, headers: HeaderMap) -> Result<Response> {
-    let res = client
-        .get(url)
-        .headers(headers)
-        .body("")
-        .send()
-        .await
-        .map_err(Error::Http);
+/// Send a GET request, retrying connection errors, timeouts and 5xx responses
+/// with exponential backoff. 4xx responses are returned immediately.
+async fn send_request(
+    client: &Client,
+    url: &str,
+    headers: HeaderMap,
+    config: &Config,
+) -> Result<Response> {
+    let mut attempt = 0;
+    loop {
+        let res = client
+            .get(url)
+            .headers(headers.clone())
+            .body("")
+            .send()
+            .await;
+
+        let transient = match &res {
+            Ok(resp) => resp.status().is_server_error(),
+            Err(err) => err.is_connect() || err.is_timeout(),
+        };
+        if !transient || attempt >= config.max_retries {
+            if let Err(err) = &res {
+                error!("backend request to {} failed: {}", url, err);
+            }
+            return res.map_err(Error::Http);
+        }
 
-    if res.is_err() {
-        println!("Error: {:?}", res);
+        attempt += 1;
+        match &res {
+            Ok(resp) => warn!(
+                "backend request to {} responded with {}, retrying ({}/{})",
+                url,
+                resp.status(),
+                attempt,
+                config.max_retries
+            ),
+            Err(err) => warn!(
+                "backend request to {} failed, retrying ({}/{}): {}",
+                url, attempt, config.max_retries, err
+            ),
+        }
+        metrics::record_backend_retry();
+        tokio::time::sleep(retry_delay(config.retry_base_delay_ms, attempt)).await;
     }
+}
 
-    res
+fn retry_delay(base_delay_ms: u64, attempt: u32) -> Duration {
+    Duration::from_millis(base_delay_ms.saturating_mul(1 << (attempt - 1).min(16)))
 }
 
 fn generate_sigv4_headers(
@@ -93,8 +343,17 @@ fn generate_sigv4_headers(
 ) -> HeaderMap {
     let mut headers = HeaderMap::new();
 
-    // Extract host from URL
-    let host = url.split('/').nth(2).unwrap_or_default();
+    // The host header must match the request URL, including any non-default port
+    let host = Url::parse(url)
+        .ok()
+        .and_then(|url| {
+            let host = url.host_str()?.to_string();
+            Some(match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host,
+            })
+        })
+        .unwrap_or_default();
 
     headers.insert("host", host.parse().unwrap());
     headers.insert(
@@ -149,25 +408,252 @@ mod tests {
     use crate::config;
     use chrono::TimeZone;
     use reqwest::header::HeaderValue;
+    use rstest::rstest;
 
     // Mock configuration for testing
     fn mock_config() -> config::Config {
         config::Config {
             otel_collector_endpoint: None,
+            log_format: config::LogFormat::default(),
+            cache_dir: None,
+            redis: None,
+            tls: None,
+            lru_max_bytes: None,
+            allowed_hosts: None,
+            overlay_hosts: vec![],
+            rate_limit: None,
+            circuit_breaker: None,
+            cors: None,
+            max_width: None,
+            max_height: None,
+            max_pixels: None,
+            max_source_bytes: None,
+            max_source_pixels: None,
+            file_root: None,
+            default_quality: config::QualityConfig::default(),
+            max_dpr: 3.0,
+            dpr_quality: vec![],
+            presets: std::collections::BTreeMap::new(),
+            warmup: vec![],
+            save_data_quality: 50,
+            max_concurrent_processing: None,
+            processing_queue_timeout_ms: 5000,
+            max_retries: 0,
+            retry_base_delay_ms: 100,
             server_address: "127.0.0.1:9090".parse().unwrap(),
             management_address: "127.0.0.1:9091".parse().unwrap(),
             read_timeout: 10,
             routing: vec![],
             proxies: vec![],
             signing_secret: Some("super_secret_key".to_string()),
+            signing_algorithm: config::SigningAlgorithm::default(),
             s3: Some(config::S3Config {
                 access_key_id: "test-access-key".to_string(),
                 secret_access_key: "test-secret-key".to_string(),
                 region: "test-region".to_string(),
+                endpoint: None,
+                path_style: false,
             }),
         }
     }
 
+    #[test]
+    fn test_http_date() {
+        let date = chrono::Utc::with_ymd_and_hms(&chrono::Utc, 2024, 2, 20, 12, 0, 0).unwrap();
+        assert_eq!(format_http_date(&date), "Tue, 20 Feb 2024 12:00:00 GMT");
+        assert_eq!(parse_http_date("Tue, 20 Feb 2024 12:00:00 GMT"), Some(date));
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[rstest]
+    #[case::png("data:image/png;base64,iVBORw0K", Some(b"\x89PNG\r\n".to_vec()))]
+    #[case::not_base64("data:image/png,hello", None)]
+    #[case::invalid_base64("data:image/png;base64,!!!", None)]
+    #[case::not_image("data:text/plain;base64,aGVsbG8=", None)]
+    fn test_get_file_from_data(#[case] uri: &str, #[case] expected: Option<Vec<u8>>) {
+        assert_eq!(
+            get_file_from_data(uri).ok().map(|file| file.bytes),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::no_list("https://example.com/a.jpg", None, true)]
+    #[case::allowed("https://example.com/a.jpg", Some(vec!["example.com"]), true)]
+    #[case::case_insensitive("https://EXAMPLE.com/a.jpg", Some(vec!["example.com"]), true)]
+    #[case::subdomain("https://cdn.example.com/a.jpg", Some(vec!["example.com"]), false)]
+    #[case::other("https://169.254.169.254/latest", Some(vec!["example.com"]), false)]
+    #[case::empty_list("https://example.com/a.jpg", Some(vec![]), false)]
+    fn test_host_allowed(
+        #[case] url: &str,
+        #[case] allowed_hosts: Option<Vec<&str>>,
+        #[case] expected: bool,
+    ) {
+        let allowed_hosts: Option<Vec<String>> =
+            allowed_hosts.map(|hosts| hosts.into_iter().map(ToString::to_string).collect());
+        assert_eq!(
+            host_allowed(&Url::parse(url).unwrap(), allowed_hosts.as_deref()),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::endpoint(
+        "https://images.example.com/logos/",
+        "https://images.example.com/logos/a.png",
+        true
+    )]
+    #[case::traversal(
+        "https://images.example.com/logos/",
+        "https://images.example.com/logos/../private/a.png",
+        false
+    )]
+    #[case::encoded_traversal(
+        "https://images.example.com/logos/",
+        "https://images.example.com/logos/%2e%2e/private/a.png",
+        false
+    )]
+    #[case::allowed_host(
+        "https://images.example.com/logos/",
+        "https://cdn.example.com/a.png",
+        true
+    )]
+    #[case::other_host(
+        "https://images.example.com/logos/",
+        "http://169.254.169.254/latest/meta-data",
+        false
+    )]
+    #[case::other_host_prefix(
+        "https://images.example.com/logos/",
+        "https://images.example.com.evil.com/logos/a.png",
+        false
+    )]
+    #[case::other_port(
+        "https://images.example.com/logos/",
+        "https://images.example.com:8443/logos/a.png",
+        false
+    )]
+    #[case::file("https://images.example.com/logos/", "file:///etc/passwd", false)]
+    #[case::s3("https://images.example.com/logos/", "s3://bucket/a.png", false)]
+    #[case::allowed_host_other_scheme(
+        "https://images.example.com/logos/",
+        "s3://cdn.example.com/a.png",
+        false
+    )]
+    #[case::data(
+        "https://images.example.com/logos/",
+        "data:image/png;base64,iVBORw0K",
+        true
+    )]
+    #[case::path_without_slash(
+        "https://images.example.com/logos",
+        "https://images.example.com/logos/a.png",
+        true
+    )]
+    #[case::sibling_path(
+        "https://images.example.com/logos",
+        "https://images.example.com/logos-secret/a.png",
+        false
+    )]
+    #[case::bucket("s3://bucket", "s3://bucket/a.png", true)]
+    #[case::bucket_with_slash("s3://bucket/", "s3://bucket/a.png", true)]
+    #[case::other_bucket("s3://bucket", "s3://bucket-other/a.png", false)]
+    #[case::other_bucket_with_slash("s3://bucket/", "s3://bucket-other/a.png", false)]
+    fn test_overlay_allowed(#[case] endpoint: &str, #[case] url: &str, #[case] expected: bool) {
+        assert_eq!(
+            overlay_allowed(
+                &Url::parse(url).unwrap(),
+                endpoint,
+                &["cdn.example.com".to_string()]
+            ),
+            expected
+        );
+    }
+
+    /// Serve every request with `status` from a local origin, returning its address
+    async fn mock_origin(status: u16) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let status = axum::http::StatusCode::from_u16(status).unwrap();
+        let router = axum::Router::new().fallback(move || async move { (status, "error page") });
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        addr
+    }
+
+    #[rstest]
+    #[case::not_found(404, "not_found")]
+    #[case::gone(410, "not_found")]
+    #[case::forbidden(403, "upstream")]
+    #[case::server_error(502, "upstream")]
+    #[case::ok(200, "ok")]
+    #[tokio::test]
+    async fn test_get_file_from_http_status(#[case] status: u16, #[case] expected: &str) {
+        let addr = mock_origin(status).await;
+        let result = get_file_from_backend(
+            &format!("http://{addr}/cat.jpg"),
+            &Client::new(),
+            &mock_config(),
+            HeaderMap::new(),
+        )
+        .await;
+        let outcome = match result {
+            Ok(file) => {
+                assert_eq!(file.bytes, b"error page");
+                "ok"
+            }
+            Err(Error::NotFound) => "not_found",
+            Err(Error::Upstream(code)) => {
+                assert_eq!(code, status);
+                "upstream"
+            }
+            Err(err) => panic!("unexpected error: {err}"),
+        };
+        assert_eq!(outcome, expected);
+    }
+
+    #[tokio::test]
+    async fn test_s3_url_without_bucket() {
+        let client = Client::new();
+        assert!(matches!(
+            get_file_from_backend("s3:foo", &client, &mock_config(), HeaderMap::new()).await,
+            Err(Error::InvalidBackend)
+        ));
+    }
+
+    #[rstest]
+    #[case::first(1, 100)]
+    #[case::second(2, 200)]
+    #[case::fourth(4, 800)]
+    fn test_retry_delay(#[case] attempt: u32, #[case] expected: u64) {
+        assert_eq!(retry_delay(100, attempt), Duration::from_millis(expected));
+    }
+
+    #[rstest]
+    #[case::inside("animated.gif", true)]
+    #[case::traversal("../../Cargo.toml", false)]
+    #[case::missing("missing.gif", false)]
+    #[tokio::test]
+    async fn test_get_file_from_file_root(#[case] path: &str, #[case] found: bool) {
+        let root = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+        let config = config::Config {
+            file_root: Some(root.to_string()),
+            ..mock_config()
+        };
+        let result = get_file_from_file(&format!("{root}/{path}"), &config).await;
+        assert_eq!(result.is_ok(), found);
+        if !found {
+            assert!(matches!(result, Err(Error::NotFound)));
+        }
+    }
+
+    #[rstest]
+    #[case(99, true)]
+    #[case(100, true)]
+    #[case(101, false)]
+    fn test_check_source_bytes(#[case] length: u64, #[case] allowed: bool) {
+        assert_eq!(check_source_bytes(length, 100).is_ok(), allowed);
+    }
+
     #[test]
     fn test_generate_signature() {
         let datetime = chrono::Utc::with_ymd_and_hms(&chrono::Utc, 2024, 2, 20, 12, 0, 0).unwrap();
@@ -179,6 +665,90 @@ mod tests {
         assert_eq!(sig, expected);
     }
 
+    #[rstest]
+    #[case::aws(
+        None,
+        false,
+        "http://test-bucket.s3.test-region.amazonaws.com/test/file.txt"
+    )]
+    #[case::endpoint(
+        Some("http://minio:9000"),
+        false,
+        "http://test-bucket.minio:9000/test/file.txt"
+    )]
+    #[case::endpoint_https(
+        Some("https://storage.example.com"),
+        false,
+        "https://test-bucket.storage.example.com/test/file.txt"
+    )]
+    #[case::path_style(
+        None,
+        true,
+        "http://s3.test-region.amazonaws.com/test-bucket/test/file.txt"
+    )]
+    #[case::endpoint_path_style(
+        Some("http://minio:9000"),
+        true,
+        "http://minio:9000/test-bucket/test/file.txt"
+    )]
+    fn test_s3_url(
+        #[case] endpoint: Option<&str>,
+        #[case] path_style: bool,
+        #[case] expected: &str,
+    ) {
+        let mut s3config = mock_config().s3.unwrap();
+        s3config.endpoint = endpoint.map(ToString::to_string);
+        s3config.path_style = path_style;
+        assert_eq!(
+            s3_url("test-bucket", "/test/file.txt", &s3config).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_generate_signature_virtual_hosted_style() {
+        let datetime = chrono::Utc::with_ymd_and_hms(&chrono::Utc, 2024, 2, 20, 12, 0, 0).unwrap();
+        let config = mock_config();
+        let url = s3_url("test-bucket", "/test/file.txt", config.s3.as_ref().unwrap()).unwrap();
+        let headers = generate_sigv4_headers(&datetime, &url, &config);
+        let sig = generate_sigv4_signature("GET", &url, &datetime, &headers, &config);
+        assert_eq!(
+            headers.get("host").unwrap(),
+            &HeaderValue::from_static("test-bucket.s3.test-region.amazonaws.com")
+        );
+        let expected = "AWS4-HMAC-SHA256 Credential=test-access-key/20240220/test-region/s3/aws4_request,SignedHeaders=authorization;host;x-amz-content-sha256;x-amz-date,Signature=f2fd6ad1970f41610dabb7a31fe53c4c7fafc44c14166ac3f3de2e2af91875b5";
+        assert_eq!(sig, expected);
+    }
+
+    #[test]
+    fn test_generate_signature_path_style() {
+        let datetime = chrono::Utc::with_ymd_and_hms(&chrono::Utc, 2024, 2, 20, 12, 0, 0).unwrap();
+        let mut config = mock_config();
+        if let Some(s3config) = config.s3.as_mut() {
+            s3config.path_style = true;
+        }
+        let url = s3_url("test-bucket", "/test/file.txt", config.s3.as_ref().unwrap()).unwrap();
+        let headers = generate_sigv4_headers(&datetime, &url, &config);
+        let sig = generate_sigv4_signature("GET", &url, &datetime, &headers, &config);
+        assert_eq!(
+            headers.get("host").unwrap(),
+            &HeaderValue::from_static("s3.test-region.amazonaws.com")
+        );
+        let expected = "AWS4-HMAC-SHA256 Credential=test-access-key/20240220/test-region/s3/aws4_request,SignedHeaders=authorization;host;x-amz-content-sha256;x-amz-date,Signature=742113fe76c0108d8d301c15765719f09d658bb360378dab85477bf03cf30ef3";
+        assert_eq!(sig, expected);
+    }
+
+    #[test]
+    fn test_generate_headers_endpoint_port() {
+        let datetime = chrono::Utc::with_ymd_and_hms(&chrono::Utc, 2024, 2, 20, 12, 0, 0).unwrap();
+        let url = "http://test-bucket.minio:9000/test/file.txt";
+        let headers = generate_sigv4_headers(&datetime, url, &mock_config());
+        assert_eq!(
+            headers.get("host").unwrap(),
+            &HeaderValue::from_static("test-bucket.minio:9000")
+        );
+    }
+
     #[test]
     fn test_generate_headers() {
         // Fixed datetime for testing
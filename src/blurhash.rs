@@ -0,0 +1,140 @@
+//! An encoder for [BlurHash](https://blurha.sh), a compact representation of a
+//! placeholder for an image.
+
+use std::f64::consts::PI;
+
+const CHARACTERS: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Encode interleaved 8-bit sRGB pixels as a BlurHash with the given number of
+/// horizontal and vertical components, each between 1 and 9. Bands beyond the
+/// first three are ignored, and a single band is treated as greyscale.
+#[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+pub fn encode(
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+    bands: usize,
+    components_x: u32,
+    components_y: u32,
+) -> String {
+    let mut factors = Vec::with_capacity((components_x * components_y) as usize);
+    for j in 0..components_y {
+        for i in 0..components_x {
+            factors.push(factor(pixels, width, height, bands, i, j));
+        }
+    }
+    let (dc, ac) = factors.split_first().expect("at least one component");
+
+    let mut hash = String::with_capacity(4 + 2 * factors.len());
+    encode83(&mut hash, (components_x - 1) + (components_y - 1) * 9, 1);
+
+    let maximum = if ac.is_empty() {
+        encode83(&mut hash, 0, 1);
+        1.0
+    } else {
+        let actual = ac.iter().flatten().fold(0.0_f64, |max, c| max.max(c.abs()));
+        let quantised = (actual * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+        encode83(&mut hash, quantised, 1);
+        f64::from(quantised + 1) / 166.0
+    };
+
+    let [r, g, b] = dc.map(linear_to_srgb);
+    encode83(&mut hash, (r << 16) + (g << 8) + b, 4);
+
+    for component in ac {
+        let [r, g, b] = component.map(|c| {
+            (sign_pow(c / maximum, 0.5) * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        });
+        encode83(&mut hash, r * 19 * 19 + g * 19 + b, 2);
+    }
+    hash
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn factor(pixels: &[u8], width: usize, height: usize, bands: usize, i: u32, j: u32) -> [f64; 3] {
+    let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+    let mut sum = [0.0; 3];
+    for y in 0..height {
+        let basis_y = (PI * f64::from(j) * y as f64 / height as f64).cos();
+        for x in 0..width {
+            let basis = basis_y * (PI * f64::from(i) * x as f64 / width as f64).cos();
+            let pixel = &pixels[(y * width + x) * bands..];
+            for (channel, total) in sum.iter_mut().enumerate() {
+                *total += basis * srgb_to_linear(pixel[channel.min(bands - 1)]);
+            }
+        }
+    }
+    let scale = normalisation / (width * height) as f64;
+    sum.map(|total| total * scale)
+}
+
+fn srgb_to_linear(value: u8) -> f64 {
+    let v = f64::from(value) / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn linear_to_srgb(value: f64) -> u32 {
+    let v = value.clamp(0.0, 1.0);
+    if v <= 0.003_130_8 {
+        (v * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * v.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+fn sign_pow(value: f64, exponent: f64) -> f64 {
+    value.abs().powf(exponent).copysign(value)
+}
+
+fn encode83(hash: &mut String, value: u32, length: u32) {
+    for i in 1..=length {
+        let digit = (value / 83_u32.pow(length - i)) % 83;
+        hash.push(char::from(CHARACTERS[digit as usize]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(0, 1, "0")]
+    #[case(82, 1, "~")]
+    #[case(83, 2, "10")]
+    #[case(3429, 2, "fQ")]
+    #[case(16_777_215, 4, "TSUA")]
+    fn test_encode83(#[case] value: u32, #[case] length: u32, #[case] expected: &str) {
+        let mut hash = String::new();
+        encode83(&mut hash, value, length);
+        assert_eq!(hash, expected);
+    }
+
+    #[rstest]
+    #[case(1, 1, "00TSUA")]
+    #[case(4, 3, "L")]
+    #[case(9, 9, "|")]
+    fn test_encode(#[case] x: u32, #[case] y: u32, #[case] prefix: &str) {
+        let pixels = vec![255; 8 * 8 * 3];
+        let hash = encode(&pixels, 8, 8, 3, x, y);
+        assert_eq!(hash.len(), 4 + 2 * (x * y) as usize);
+        assert!(hash.starts_with(prefix));
+        // A white image has a white average colour
+        assert_eq!(&hash[2..6], "TSUA");
+    }
+
+    #[test]
+    fn test_encode_greyscale() {
+        let grey = encode(&[128; 16], 4, 4, 1, 4, 3);
+        let rgb = encode(&[128; 48], 4, 4, 3, 4, 3);
+        assert_eq!(grey, rgb);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::config::CircuitBreakerConfig;
+use crate::metrics;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Default)]
+struct Circuit {
+    failures: u32,
+    opened: Option<Instant>,
+    /// When the single request allowed through a half-open circuit started
+    probing: Option<Instant>,
+}
+
+/// Fails fast for backend hosts that keep failing. A circuit opens after
+/// `failure_threshold` consecutive failures, rejects requests for the cooldown,
+/// then lets one request through to decide whether to close again.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        Self {
+            threshold: config.failure_threshold.max(1),
+            cooldown: Duration::from_secs(config.cooldown_seconds),
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a request to the host may be sent.
+    pub fn allow(&self, host: &str) -> bool {
+        self.allow_at(host, Instant::now())
+    }
+
+    fn allow_at(&self, host: &str, now: Instant) -> bool {
+        let mut circuits = self.circuits.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(circuit) = circuits.get_mut(host) else {
+            return true;
+        };
+        match self.state(circuit, now) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                // A probe that never reported back, such as a cancelled request, expires
+                if circuit
+                    .probing
+                    .is_some_and(|started| now.duration_since(started) < self.cooldown)
+                {
+                    return false;
+                }
+                circuit.probing = Some(now);
+                metrics::record_circuit_state(host, CircuitState::HalfOpen);
+                true
+            }
+        }
+    }
+
+    /// Record the outcome of a request to the host.
+    pub fn record(&self, host: &str, success: bool) {
+        self.record_at(host, success, Instant::now());
+    }
+
+    fn record_at(&self, host: &str, success: bool, now: Instant) {
+        let mut circuits = self.circuits.lock().unwrap_or_else(PoisonError::into_inner);
+        if success {
+            if circuits
+                .remove(host)
+                .is_some_and(|circuit| circuit.opened.is_some())
+            {
+                metrics::record_circuit_state(host, CircuitState::Closed);
+            }
+            return;
+        }
+
+        let circuit = circuits.entry(host.to_string()).or_default();
+        circuit.failures += 1;
+        if circuit.probing.take().is_some() || circuit.failures >= self.threshold {
+            circuit.opened = Some(now);
+            metrics::record_circuit_state(host, CircuitState::Open);
+        }
+    }
+
+    fn state(&self, circuit: &Circuit, now: Instant) -> CircuitState {
+        match circuit.opened {
+            None => CircuitState::Closed,
+            Some(opened) if now.duration_since(opened) < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_threshold: 2,
+            cooldown_seconds: 10,
+        });
+        let start = Instant::now();
+
+        // The circuit opens after consecutive failures
+        breaker.record_at("a", false, start);
+        assert!(breaker.allow_at("a", start));
+        breaker.record_at("a", false, start);
+        assert!(!breaker.allow_at("a", start));
+
+        // Other hosts are unaffected
+        assert!(breaker.allow_at("b", start));
+
+        // After the cooldown a single probe is let through
+        let later = start + Duration::from_secs(10);
+        assert!(breaker.allow_at("a", later));
+        assert!(!breaker.allow_at("a", later));
+
+        // A failed probe opens the circuit again
+        breaker.record_at("a", false, later);
+        assert!(!breaker.allow_at("a", later + Duration::from_secs(1)));
+
+        // A successful probe closes it
+        let much_later = later + Duration::from_secs(10);
+        assert!(breaker.allow_at("a", much_later));
+        breaker.record_at("a", true, much_later);
+        assert!(breaker.allow_at("a", much_later));
+        assert!(breaker.allow_at("a", much_later));
+    }
+
+    #[test]
+    fn test_circuit_breaker_success_resets_failures() {
+        let breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_threshold: 2,
+            cooldown_seconds: 10,
+        });
+        let start = Instant::now();
+
+        breaker.record_at("a", false, start);
+        breaker.record_at("a", true, start);
+        breaker.record_at("a", false, start);
+        assert!(breaker.allow_at("a", start));
+    }
+}
@@ -0,0 +1,238 @@
+//! Command line tools that share the server's configuration and option parsing.
+
+use crate::config::{self, Config, SigningAlgorithm};
+use crate::image::{self, Overlays};
+use crate::options::{self, ImageFormat, ImageOptions};
+use crate::service;
+use axum::extract::Query;
+use axum::http::Uri;
+use opentelemetry::Context as TraceContext;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use url::Url;
+use url::form_urlencoded;
+
+/// Sign a URL the same way the server verifies it, for `shrinkray sign <url>`.
+///
+/// The secret comes from `--secret`, or the matching route's or global
+/// `signing_secret` in the configuration given by `--config`.
+pub fn sign(args: &[String]) -> Result<String, Box<dyn Error>> {
+    let mut url = None;
+    let mut secret = None;
+    let mut config_file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--secret" => secret = Some(args.next().ok_or("--secret needs a value")?.clone()),
+            "--config" => config_file = Some(args.next().ok_or("--config needs a value")?.clone()),
+            flag if flag.starts_with("--") => return Err(format!("unknown flag `{flag}`").into()),
+            _ if url.is_some() => return Err("only one url can be signed at a time".into()),
+            _ => url = Some(arg.clone()),
+        }
+    }
+    let url = url.ok_or("usage: shrinkray sign <url> [--secret <secret>] [--config <file>]")?;
+
+    // An explicit secret doesn't need a configuration, but still uses its algorithm if there is one
+    let config = match (config::load_config(config_file.as_deref()), &secret) {
+        (Ok(config), _) => Some(config),
+        (Err(_), Some(_)) if config_file.is_none() => None,
+        (Err(err), _) => return Err(err),
+    };
+    sign_url(&url, secret.as_deref(), config.as_ref())
+}
+
+fn sign_url(
+    input: &str,
+    secret: Option<&str>,
+    config: Option<&Config>,
+) -> Result<String, Box<dyn Error>> {
+    // Paths without a host are signed and printed as paths
+    let absolute = Url::parse(input).is_ok();
+    let mut url = Url::parse("http://localhost")?.join(input)?;
+
+    let options = parse_options(url.query().unwrap_or_default())?;
+    let route_secret = config.and_then(|config| {
+        config
+            .routing
+            .iter()
+            .find(|route| {
+                let prefix = &route.path[..route.path.find('{').unwrap_or(0)];
+                url.path()
+                    .strip_prefix('/')
+                    .is_some_and(|path| path.starts_with(prefix))
+            })
+            .and_then(|route| route.signing_secret.as_deref())
+    });
+    let secret = secret
+        .or(route_secret)
+        .or_else(|| config.and_then(|config| config.signing_secret.as_deref()))
+        .ok_or("no signing secret is configured, pass one with --secret")?;
+    let algorithm = config.map_or(SigningAlgorithm::default(), |config| {
+        config.signing_algorithm
+    });
+    let signature = options.sign(secret, url.path(), algorithm);
+
+    // Keep the parameters as given so the server parses the same options
+    let params: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| name != "sig")
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(params)
+        .append_pair("sig", &signature);
+
+    if absolute {
+        Ok(url.to_string())
+    } else {
+        Ok(format!(
+            "{}?{}",
+            url.path(),
+            url.query().unwrap_or_default()
+        ))
+    }
+}
+
+/// Transform a local file without starting the server, for
+/// `shrinkray process --in <file> --out <file> [--<parameter> <value>...]`.
+///
+/// Other flags are URL parameters, e.g. `--w 300 --fit crop`, and the output
+/// format defaults to the extension of `--out`.
+pub fn process(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut input = None;
+    let mut output = None;
+    let mut config_file = None;
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(format!("unexpected argument `{arg}`").into());
+        };
+        let value = args
+            .next()
+            .ok_or_else(|| format!("--{flag} needs a value"))?;
+        match flag {
+            "in" => input = Some(value),
+            "out" => output = Some(value),
+            "config" => config_file = Some(value.as_str()),
+            name => {
+                query.append_pair(name, value);
+            }
+        }
+    }
+    let usage = "usage: shrinkray process --in <file> --out <file> [--<parameter> <value>...]";
+    let (input, output) = input.zip(output).ok_or(usage)?;
+
+    let config = config::load_config(config_file)?;
+    let mut options = parse_process_options(&query.finish(), output, &config)?;
+
+    let bytes = std::fs::read(input)?;
+    let vips_app = service::create_vips_app();
+    let image = image::process_image(
+        &bytes,
+        &Overlays::default(),
+        &mut options,
+        &config,
+        &TraceContext::current(),
+    )
+    .map_err(|err| {
+        let error_buffer = vips_app.error_buffer().unwrap_or("").replace('\n', "");
+        format!("{err}: {error_buffer}")
+    })?;
+    std::fs::write(output, image.bytes)?;
+    Ok(())
+}
+
+fn parse_process_options(
+    query: &str,
+    output: &str,
+    config: &Config,
+) -> Result<ImageOptions, Box<dyn Error>> {
+    let format = match Path::new(output).extension().and_then(|ext| ext.to_str()) {
+        Some("jpg") => Some(ImageFormat::Jpeg),
+        Some(ext) => ext.parse().ok(),
+        None => None,
+    };
+    let defaults = format
+        .map(|format| BTreeMap::from([("fm".to_string(), format.to_string())]))
+        .unwrap_or_default();
+    let mut options = parse_options(&options::merge_query(query, &defaults))?;
+    if let Some(name) = &options.preset {
+        let preset = config
+            .presets
+            .get(name)
+            .ok_or_else(|| format!("unknown preset `{name}`"))?;
+        options = options::with_preset(query, &preset.0)?;
+        if options.format.is_none() {
+            options.format = format;
+        }
+    }
+    // Overlays are fetched from the backend, which isn't used here
+    if options.watermark.is_some() || options.overlay.is_some() {
+        return Err("watermarks and overlays aren't supported when processing files".into());
+    }
+    options.clamp_device_pixel_ratio(config.max_dpr);
+    Ok(options)
+}
+
+fn parse_options(query: &str) -> Result<ImageOptions, Box<dyn Error>> {
+    let uri: Uri = format!("/?{query}").parse()?;
+    Query::<ImageOptions>::try_from_uri(&uri)
+        .map(|query| query.0)
+        .map_err(|rejection| rejection.body_text().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::absolute("https://images.example.com/samples/08.jpg?w=300&fit=crop")]
+    #[case::path("/samples/08.jpg?w=300&fit=crop")]
+    #[case::replaces_signature("/samples/08.jpg?w=300&sig=abc&fit=crop")]
+    fn test_sign_url(#[case] input: &str) {
+        let signed = sign_url(input, Some("secret"), None).unwrap();
+        let url = Url::parse("http://localhost")
+            .unwrap()
+            .join(&signed)
+            .unwrap();
+        let options = parse_options(url.query().unwrap()).unwrap();
+        assert!(options.verify_signature("secret", url.path(), SigningAlgorithm::default()));
+        assert_eq!(signed.matches("sig=").count(), 1);
+        assert_eq!(
+            signed.starts_with("https://"),
+            input.starts_with("https://")
+        );
+    }
+
+    #[test]
+    fn test_sign_url_without_secret() {
+        assert!(sign_url("/samples/08.jpg?w=300", None, None).is_err());
+    }
+
+    #[rstest]
+    #[case::from_extension("w=300&fit=crop", "b.webp", Some(ImageFormat::Webp))]
+    #[case::jpg_extension("w=300", "b.jpg", Some(ImageFormat::Jpeg))]
+    #[case::explicit_format("w=300&fm=png", "b.webp", Some(ImageFormat::Png))]
+    #[case::unknown_extension("w=300", "b.out", None)]
+    fn test_parse_process_options(
+        #[case] query: &str,
+        #[case] output: &str,
+        #[case] expected: Option<ImageFormat>,
+    ) {
+        let options = parse_process_options(query, output, &Config::default()).unwrap();
+        assert_eq!(options.width, Some(300));
+        assert_eq!(options.format, expected);
+    }
+
+    #[rstest]
+    #[case::invalid_value("w=wide")]
+    #[case::watermark("wm=https://example.com/logo.png")]
+    #[case::unknown_preset("preset=missing")]
+    fn test_parse_process_options_invalid(#[case] query: &str) {
+        assert!(parse_process_options(query, "b.webp", &Config::default()).is_err());
+    }
+}
@@ -1,13 +1,137 @@
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
 use std::env;
-use std::fs::File;
+use std::fmt;
 use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::options::{ImageFormat, with_preset};
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct S3Config {
     pub access_key_id: String,
     pub secret_access_key: String,
     pub region: String,
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub path_style: bool,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RedisConfig {
+    pub url: String,
+    pub ttl_seconds: u64,
+}
+
+/// PEM files used to serve HTTPS
+#[derive(Deserialize, Clone, Debug)]
+pub struct TlsConfig {
+    /// Certificate chain, leaf first
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RateLimitConfig {
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CorsConfig {
+    /// Origins allowed to read images, or `*` for any
+    pub allowed_origins: Vec<String>,
+    /// How long in seconds browsers may cache preflight responses
+    pub max_age: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CircuitBreakerConfig {
+    pub failure_threshold: u32,
+    pub cooldown_seconds: u64,
+}
+
+/// HMAC algorithm used for URL signatures
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SigningAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl From<SigningAlgorithm> for ring::hmac::Algorithm {
+    fn from(algorithm: SigningAlgorithm) -> Self {
+        match algorithm {
+            SigningAlgorithm::Sha256 => ring::hmac::HMAC_SHA256,
+            SigningAlgorithm::Sha512 => ring::hmac::HMAC_SHA512,
+        }
+    }
+}
+
+/// How log events are written to stdout
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+/// Output quality used for each format when a request doesn't set `q`
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct QualityConfig {
+    pub jpeg: Option<i32>,
+    pub webp: Option<i32>,
+    pub avif: Option<i32>,
+    pub png: Option<i32>,
+}
+
+/// Image parameters applied by `?preset=name`, using the query string names.
+/// Numbers and booleans are accepted as well as strings.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(try_from = "BTreeMap<String, Value>")]
+pub struct Preset(pub BTreeMap<String, String>);
+
+impl TryFrom<BTreeMap<String, Value>> for Preset {
+    type Error = String;
+
+    fn try_from(params: BTreeMap<String, Value>) -> Result<Self, Self::Error> {
+        params
+            .into_iter()
+            .map(|(name, value)| match value {
+                Value::String(value) => Ok((name, value)),
+                Value::Number(value) => Ok((name, value.to_string())),
+                Value::Bool(value) => Ok((name, value.to_string())),
+                _ => Err(format!("preset parameter `{name}` must be a scalar")),
+            })
+            .collect::<Result<_, _>>()
+            .map(Preset)
+    }
+}
+
+/// A multiplier for the default quality of requests at or above a device pixel ratio
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct DprQuality {
+    pub dpr: f64,
+    pub factor: f64,
+}
+
+impl QualityConfig {
+    pub fn get(&self, format: ImageFormat) -> Option<i32> {
+        match format {
+            ImageFormat::Jpeg => self.jpeg,
+            ImageFormat::Webp => self.webp,
+            ImageFormat::Avif => self.avif,
+            ImageFormat::Png => self.png,
+            _ => None,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -19,22 +143,849 @@ pub struct Config {
     pub proxies: Vec<ipnet::IpNet>,
     pub s3: Option<S3Config>,
     pub signing_secret: Option<String>,
+    #[serde(default)]
+    pub signing_algorithm: SigningAlgorithm,
     pub otel_collector_endpoint: Option<String>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    pub cache_dir: Option<String>,
+    pub redis: Option<RedisConfig>,
+    pub tls: Option<TlsConfig>,
+    pub lru_max_bytes: Option<usize>,
+    pub allowed_hosts: Option<Vec<String>>,
+    /// Hosts that watermark and overlay images may come from besides the route's endpoint
+    #[serde(default)]
+    pub overlay_hosts: Vec<String>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    pub cors: Option<CorsConfig>,
+    pub max_width: Option<i32>,
+    pub max_height: Option<i32>,
+    pub max_pixels: Option<u64>,
+    pub max_source_bytes: Option<u64>,
+    pub max_source_pixels: Option<u64>,
+    /// Directory that `file://` sources must be inside
+    pub file_root: Option<String>,
+    #[serde(default)]
+    pub default_quality: QualityConfig,
+    #[serde(default = "default_max_dpr")]
+    pub max_dpr: f64,
+    #[serde(default)]
+    pub dpr_quality: Vec<DprQuality>,
+    #[serde(default)]
+    pub presets: BTreeMap<String, Preset>,
+    /// Request paths, with their query strings, to process and cache on startup
+    #[serde(default)]
+    pub warmup: Vec<String>,
+    #[serde(default = "default_save_data_quality")]
+    pub save_data_quality: i32,
+    pub max_concurrent_processing: Option<usize>,
+    #[serde(default = "default_processing_queue_timeout_ms")]
+    pub processing_queue_timeout_ms: u64,
+    #[serde(default)]
+    pub max_retries: u32,
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+}
+
+fn default_max_dpr() -> f64 {
+    3.0
+}
+
+fn default_save_data_quality() -> i32 {
+    50
+}
+
+fn default_processing_queue_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    100
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct ConfigRouting {
     pub path: String,
     pub endpoint: String,
+    pub signing_secret: Option<String>,
+    #[serde(default)]
+    pub backend_headers: BackendHeaders,
+    /// The `Cache-Control` header for responses, defaulting to one year
+    pub cache_control: Option<String>,
+    /// A backend URL for an image served when the requested one isn't found
+    pub fallback_image: Option<String>,
+    /// The status for fallback image responses, defaulting to 404
+    pub fallback_status: Option<u16>,
+    /// Accept transformations as the first path segment, e.g. `w_300,h_200/cat.jpg`
+    #[serde(default)]
+    pub path_options: bool,
+    /// Send a weak ETag derived from the URL and options, and answer matching
+    /// revalidations without fetching the source. Only for sources that don't change.
+    #[serde(default)]
+    pub weak_etag: bool,
+}
+
+impl ConfigRouting {
+    pub fn fallback_status(&self) -> StatusCode {
+        self.fallback_status
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .unwrap_or(StatusCode::NOT_FOUND)
+    }
+}
+
+/// Headers sent with every HTTP backend request for a route. Values are often
+/// credentials, so they're redacted from debug output.
+#[derive(Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct BackendHeaders(pub BTreeMap<String, String>);
+
+impl BackendHeaders {
+    /// Build the headers for a request, or the name of the first invalid header.
+    pub fn to_header_map(&self) -> Result<HeaderMap, String> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.0 {
+            let header_name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| name.clone())?;
+            let mut header_value = HeaderValue::from_str(value).map_err(|_| name.clone())?;
+            header_value.set_sensitive(true);
+            headers.insert(header_name, header_value);
+        }
+        Ok(headers)
+    }
+}
+
+impl fmt::Debug for BackendHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.keys().map(|name| (name, "<redacted>")))
+            .finish()
+    }
+}
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ConfigError {
+    #[error("no routes are configured")]
+    NoRoutes,
+    #[error("route `{0}` must not start with a slash")]
+    LeadingSlash(String),
+    #[error("route `{0}` must capture the image path, e.g. `images/{{*path}}`")]
+    MissingCapture(String),
+    #[error("route `{0}` is configured more than once")]
+    DuplicatePath(String),
+    #[error("routes `{0}` and `{1}` overlap and can't be told apart")]
+    OverlappingPaths(String, String),
+    #[error("route `{0}` has an empty endpoint")]
+    EmptyEndpoint(String),
+    #[error("route `{0}` has an invalid endpoint `{1}`")]
+    InvalidEndpoint(String, String),
+    #[error("route `{0}` uses an s3 endpoint but no `s3` section is configured")]
+    MissingS3(String),
+    #[error("route `{0}` has an invalid backend header `{1}`")]
+    InvalidBackendHeader(String, String),
+    #[error("route `{0}` has an invalid cache_control `{1}`")]
+    InvalidCacheControl(String, String),
+    #[error("route `{0}` has an invalid fallback_image `{1}`")]
+    InvalidFallbackImage(String, String),
+    #[error("route `{0}` has an invalid fallback_status `{1}`")]
+    InvalidFallbackStatus(String, u16),
+    #[error("default quality for `{0}` must be between 1 and 100")]
+    InvalidQuality(ImageFormat),
+    #[error("save_data_quality must be between 1 and 100")]
+    InvalidSaveDataQuality,
+    #[error("dpr_quality for dpr `{0}` must have a factor greater than 0 and at most 1")]
+    InvalidDprQuality(f64),
+    #[error("preset `{0}` is invalid: {1}")]
+    InvalidPreset(String, String),
+    #[error("warmup entry `{0}` must be a path starting with `/`")]
+    InvalidWarmup(String),
+}
+
+impl Config {
+    /// Check the configuration for mistakes that would otherwise produce broken
+    /// routes or failing requests.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.routing.is_empty() {
+            return Err(ConfigError::NoRoutes);
+        }
+
+        for format in [
+            ImageFormat::Jpeg,
+            ImageFormat::Webp,
+            ImageFormat::Avif,
+            ImageFormat::Png,
+        ] {
+            if let Some(quality) = self.default_quality.get(format)
+                && !(1..=100).contains(&quality)
+            {
+                return Err(ConfigError::InvalidQuality(format));
+            }
+        }
+
+        if !(1..=100).contains(&self.save_data_quality) {
+            return Err(ConfigError::InvalidSaveDataQuality);
+        }
+
+        for entry in &self.dpr_quality {
+            if !(entry.dpr.is_finite() && entry.factor > 0.0 && entry.factor <= 1.0) {
+                return Err(ConfigError::InvalidDprQuality(entry.dpr));
+            }
+        }
+
+        for (name, preset) in &self.presets {
+            if let Some(param) = ["preset", "sig", "exp"]
+                .into_iter()
+                .find(|param| preset.0.contains_key(*param))
+            {
+                return Err(ConfigError::InvalidPreset(
+                    name.clone(),
+                    format!("`{param}` can't be set by a preset"),
+                ));
+            }
+            with_preset("", &preset.0)
+                .map_err(|err| ConfigError::InvalidPreset(name.clone(), err))?;
+        }
+
+        for entry in &self.warmup {
+            if !entry.starts_with('/') || entry.parse::<axum::http::Uri>().is_err() {
+                return Err(ConfigError::InvalidWarmup(entry.clone()));
+            }
+        }
+
+        for (index, route) in self.routing.iter().enumerate() {
+            let path = &route.path;
+            if path.starts_with('/') {
+                return Err(ConfigError::LeadingSlash(path.clone()));
+            }
+            if !path.contains('{') {
+                return Err(ConfigError::MissingCapture(path.clone()));
+            }
+            if route.endpoint.trim().is_empty() {
+                return Err(ConfigError::EmptyEndpoint(path.clone()));
+            }
+            let endpoint = url::Url::parse(&route.endpoint)
+                .map_err(|_| ConfigError::InvalidEndpoint(path.clone(), route.endpoint.clone()))?;
+            if endpoint.scheme() == "s3" && self.s3.is_none() {
+                return Err(ConfigError::MissingS3(path.clone()));
+            }
+            route
+                .backend_headers
+                .to_header_map()
+                .map_err(|name| ConfigError::InvalidBackendHeader(path.clone(), name))?;
+            if let Some(fallback) = &route.fallback_image
+                && url::Url::parse(fallback).is_err()
+            {
+                return Err(ConfigError::InvalidFallbackImage(
+                    path.clone(),
+                    fallback.clone(),
+                ));
+            }
+            if let Some(status) = route.fallback_status
+                && !(200..600).contains(&status)
+            {
+                return Err(ConfigError::InvalidFallbackStatus(path.clone(), status));
+            }
+            if let Some(cache_control) = &route.cache_control
+                && reqwest::header::HeaderValue::from_str(cache_control).is_err()
+            {
+                return Err(ConfigError::InvalidCacheControl(
+                    path.clone(),
+                    cache_control.clone(),
+                ));
+            }
+
+            for other in &self.routing[..index] {
+                if other.path == *path {
+                    return Err(ConfigError::DuplicatePath(path.clone()));
+                }
+                if route_shape(&other.path) == route_shape(path) {
+                    return Err(ConfigError::OverlappingPaths(
+                        other.path.clone(),
+                        path.clone(),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the host is the host of a route's endpoint, rather than one that
+    /// only appears in a request.
+    pub fn is_endpoint_host(&self, host: &str) -> bool {
+        self.routing.iter().any(|route| {
+            url::Url::parse(&route.endpoint)
+                .ok()
+                .and_then(|endpoint| endpoint.host_str().map(|h| h.eq_ignore_ascii_case(host)))
+                .unwrap_or(false)
+        })
+    }
+
+    /// The quality multiplier for a device pixel ratio, from the `dpr_quality`
+    /// entry with the highest ratio that doesn't exceed it.
+    pub fn dpr_quality_factor(&self, dpr: f64) -> Option<f64> {
+        self.dpr_quality
+            .iter()
+            .filter(|entry| entry.dpr <= dpr)
+            .max_by(|a, b| a.dpr.total_cmp(&b.dpr))
+            .map(|entry| entry.factor)
+    }
+}
+
+/// A route path with its parameter names removed, so routes that only differ
+/// by parameter name compare equal.
+fn route_shape(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            if segment.starts_with('{') {
+                "{}"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
 }
 
+/// Environment variables that override configuration values, the path of
+/// the value they replace, and whether the value is a plain string rather
+/// than JSON (for numbers, booleans and lists).
+const ENV_OVERRIDES: &[(&str, &[&str], bool)] = &[
+    ("SHRINKRAY_SERVER_ADDRESS", &["server_address"], true),
+    (
+        "SHRINKRAY_MANAGEMENT_ADDRESS",
+        &["management_address"],
+        true,
+    ),
+    ("SHRINKRAY_READ_TIMEOUT", &["read_timeout"], false),
+    ("SHRINKRAY_ROUTING", &["routing"], false),
+    ("SHRINKRAY_PROXIES", &["proxies"], false),
+    ("SHRINKRAY_SIGNING_SECRET", &["signing_secret"], true),
+    ("SHRINKRAY_SIGNING_ALGORITHM", &["signing_algorithm"], true),
+    (
+        "SHRINKRAY_OTEL_COLLECTOR_ENDPOINT",
+        &["otel_collector_endpoint"],
+        true,
+    ),
+    ("SHRINKRAY_LOG_FORMAT", &["log_format"], true),
+    ("SHRINKRAY_CACHE_DIR", &["cache_dir"], true),
+    ("SHRINKRAY_FILE_ROOT", &["file_root"], true),
+    ("SHRINKRAY_LRU_MAX_BYTES", &["lru_max_bytes"], false),
+    ("SHRINKRAY_ALLOWED_HOSTS", &["allowed_hosts"], false),
+    ("SHRINKRAY_OVERLAY_HOSTS", &["overlay_hosts"], false),
+    (
+        "SHRINKRAY_RATE_LIMIT_REQUESTS_PER_SECOND",
+        &["rate_limit", "requests_per_second"],
+        false,
+    ),
+    (
+        "SHRINKRAY_RATE_LIMIT_BURST",
+        &["rate_limit", "burst"],
+        false,
+    ),
+    (
+        "SHRINKRAY_CIRCUIT_BREAKER_FAILURE_THRESHOLD",
+        &["circuit_breaker", "failure_threshold"],
+        false,
+    ),
+    (
+        "SHRINKRAY_CIRCUIT_BREAKER_COOLDOWN_SECONDS",
+        &["circuit_breaker", "cooldown_seconds"],
+        false,
+    ),
+    (
+        "SHRINKRAY_CORS_ALLOWED_ORIGINS",
+        &["cors", "allowed_origins"],
+        false,
+    ),
+    ("SHRINKRAY_CORS_MAX_AGE", &["cors", "max_age"], false),
+    ("SHRINKRAY_MAX_WIDTH", &["max_width"], false),
+    ("SHRINKRAY_MAX_HEIGHT", &["max_height"], false),
+    ("SHRINKRAY_MAX_PIXELS", &["max_pixels"], false),
+    ("SHRINKRAY_MAX_SOURCE_BYTES", &["max_source_bytes"], false),
+    ("SHRINKRAY_MAX_SOURCE_PIXELS", &["max_source_pixels"], false),
+    ("SHRINKRAY_MAX_DPR", &["max_dpr"], false),
+    ("SHRINKRAY_DEFAULT_QUALITY", &["default_quality"], false),
+    ("SHRINKRAY_DPR_QUALITY", &["dpr_quality"], false),
+    ("SHRINKRAY_PRESETS", &["presets"], false),
+    ("SHRINKRAY_WARMUP", &["warmup"], false),
+    ("SHRINKRAY_SAVE_DATA_QUALITY", &["save_data_quality"], false),
+    (
+        "SHRINKRAY_MAX_CONCURRENT_PROCESSING",
+        &["max_concurrent_processing"],
+        false,
+    ),
+    (
+        "SHRINKRAY_PROCESSING_QUEUE_TIMEOUT_MS",
+        &["processing_queue_timeout_ms"],
+        false,
+    ),
+    ("SHRINKRAY_MAX_RETRIES", &["max_retries"], false),
+    (
+        "SHRINKRAY_RETRY_BASE_DELAY_MS",
+        &["retry_base_delay_ms"],
+        false,
+    ),
+    ("SHRINKRAY_S3_ACCESS_KEY_ID", &["s3", "access_key_id"], true),
+    (
+        "SHRINKRAY_S3_SECRET_ACCESS_KEY",
+        &["s3", "secret_access_key"],
+        true,
+    ),
+    ("SHRINKRAY_S3_REGION", &["s3", "region"], true),
+    ("SHRINKRAY_S3_ENDPOINT", &["s3", "endpoint"], true),
+    ("SHRINKRAY_S3_PATH_STYLE", &["s3", "path_style"], false),
+    ("SHRINKRAY_REDIS_URL", &["redis", "url"], true),
+    ("SHRINKRAY_TLS_CERT", &["tls", "cert"], true),
+    ("SHRINKRAY_TLS_KEY", &["tls", "key"], true),
+    (
+        "SHRINKRAY_REDIS_TTL_SECONDS",
+        &["redis", "ttl_seconds"],
+        false,
+    ),
+];
+
 pub fn read_config() -> Result<Config, Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    let file = if args.len() > 1 {
-        args[1].clone()
-    } else {
-        "config/config.json".to_string()
+    load_config(env::args().nth(1).as_deref())
+}
+
+/// Read the configuration from a file, or `config/config.json` when none is given.
+pub fn load_config(file: Option<&str>) -> Result<Config, Box<dyn std::error::Error>> {
+    let explicit = file.is_some();
+    let file = file.unwrap_or("config/config.json");
+
+    // Without an explicit file the configuration may come entirely from the environment
+    let contents = match std::fs::read_to_string(file) {
+        Ok(contents) => Some(contents),
+        Err(err) if !explicit && err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
     };
 
-    Ok(serde_json::from_reader(File::open(file)?)?)
+    let mut value = match contents {
+        None => Value::Object(Map::new()),
+        Some(contents) => parse_contents(file, &contents)?,
+    };
+
+    apply_env_overrides(&mut value, env::vars())?;
+    let config: Config = serde_json::from_value(value)?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// YAML is parsed into the same JSON value model as JSON so both share the derives
+fn parse_contents(file: &str, contents: &str) -> Result<Value, Box<dyn std::error::Error>> {
+    match Path::new(file).extension().and_then(|ext| ext.to_str()) {
+        Some("yaml" | "yml") => Ok(serde_norway::from_str(contents)?),
+        _ => Ok(serde_json::from_str(contents)?),
+    }
+}
+
+fn apply_env_overrides(
+    config: &mut Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (name, raw) in vars {
+        let Some((_, path, is_string)) = ENV_OVERRIDES.iter().find(|(var, _, _)| *var == name)
+        else {
+            continue;
+        };
+        let value = if *is_string {
+            Value::String(raw)
+        } else {
+            serde_json::from_str(&raw).map_err(|err| format!("invalid value for {name}: {err}"))?
+        };
+
+        set_path(config, path, value);
+    }
+    Ok(())
+}
+
+fn set_path(target: &mut Value, path: &[&str], value: Value) {
+    let Some((key, rest)) = path.split_first() else {
+        *target = value;
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(map) = target {
+        set_path(map.entry(*key).or_insert(Value::Null), rest, value);
+    }
+}
+
+#[cfg(test)]
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_address: "127.0.0.1:9090".parse().unwrap(),
+            management_address: "127.0.0.1:9091".parse().unwrap(),
+            read_timeout: 10,
+            routing: vec![],
+            proxies: vec![],
+            s3: None,
+            signing_secret: None,
+            signing_algorithm: SigningAlgorithm::default(),
+            otel_collector_endpoint: None,
+            log_format: LogFormat::default(),
+            cache_dir: None,
+            redis: None,
+            tls: None,
+            lru_max_bytes: None,
+            allowed_hosts: None,
+            overlay_hosts: vec![],
+            rate_limit: None,
+            circuit_breaker: None,
+            cors: None,
+            max_width: None,
+            max_height: None,
+            max_pixels: None,
+            max_source_bytes: None,
+            max_source_pixels: None,
+            file_root: None,
+            default_quality: QualityConfig::default(),
+            max_dpr: 3.0,
+            dpr_quality: vec![],
+            presets: BTreeMap::new(),
+            warmup: vec![],
+            save_data_quality: 50,
+            max_concurrent_processing: None,
+            processing_queue_timeout_ms: 5000,
+            max_retries: 0,
+            retry_base_delay_ms: 100,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use serde_json::json;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(name, value)| ((*name).to_string(), (*value).to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let mut config = json!({
+            "signing_secret": "from-file",
+            "read_timeout": 5,
+            "s3": {"access_key_id": "key", "secret_access_key": "", "region": "us-east-1"},
+        });
+        apply_env_overrides(
+            &mut config,
+            vars(&[
+                ("SHRINKRAY_SIGNING_SECRET", "1234"),
+                ("SHRINKRAY_READ_TIMEOUT", "10"),
+                ("SHRINKRAY_S3_SECRET_ACCESS_KEY", "secret"),
+                ("PATH", "/usr/bin"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            json!({
+                "signing_secret": "1234",
+                "read_timeout": 10,
+                "s3": {"access_key_id": "key", "secret_access_key": "secret", "region": "us-east-1"},
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_yaml_contents() {
+        let input = r#"
+# shrinkray configuration
+server_address: 0.0.0.0:9090
+read_timeout: 5
+signing_secret: 'it''s a secret' # trailing comment
+proxies: [10.0.0.0/8, "::1/128"]
+overlay_hosts: ["a.example.com, b.example.com"]
+routing:
+  - path: "samples/{*path}"
+    endpoint: https://shrinkray.photo/samples/#fragment
+  -
+    path: "{*path}"
+    endpoint: s3://bucket-name/
+"#;
+        assert_eq!(
+            parse_contents("config.yaml", input).unwrap(),
+            json!({
+                "server_address": "0.0.0.0:9090",
+                "read_timeout": 5,
+                "signing_secret": "it's a secret",
+                "proxies": ["10.0.0.0/8", "::1/128"],
+                "overlay_hosts": ["a.example.com, b.example.com"],
+                "routing": [
+                    {"path": "samples/{*path}", "endpoint": "https://shrinkray.photo/samples/#fragment"},
+                    {"path": "{*path}", "endpoint": "s3://bucket-name/"},
+                ],
+            })
+        );
+        assert!(parse_contents("config.yml", "key: \"value").is_err());
+        assert!(parse_contents("config.json", "read_timeout: 5").is_err());
+    }
+
+    #[test]
+    fn test_env_overrides_without_file() {
+        let mut config = Value::Object(Map::new());
+        apply_env_overrides(
+            &mut config,
+            vars(&[
+                ("SHRINKRAY_SERVER_ADDRESS", "0.0.0.0:9090"),
+                ("SHRINKRAY_MANAGEMENT_ADDRESS", "0.0.0.0:9091"),
+                ("SHRINKRAY_READ_TIMEOUT", "5"),
+                ("SHRINKRAY_PROXIES", "[]"),
+                (
+                    "SHRINKRAY_ROUTING",
+                    r#"[{"path": "{*path}", "endpoint": "s3://bucket/"}]"#,
+                ),
+                ("SHRINKRAY_S3_ACCESS_KEY_ID", "key"),
+                ("SHRINKRAY_S3_SECRET_ACCESS_KEY", "secret"),
+                ("SHRINKRAY_S3_REGION", "eu-west-1"),
+            ]),
+        )
+        .unwrap();
+
+        let config: Config = serde_json::from_value(config).unwrap();
+        assert_eq!(config.routing[0].endpoint, "s3://bucket/");
+        assert_eq!(config.s3.unwrap().secret_access_key, "secret");
+    }
+
+    #[test]
+    fn test_env_overrides_invalid() {
+        let mut config = Value::Object(Map::new());
+        assert!(
+            apply_env_overrides(&mut config, vars(&[("SHRINKRAY_READ_TIMEOUT", "soon")])).is_err()
+        );
+    }
+
+    fn routes(routes: &[(&str, &str)]) -> Config {
+        Config {
+            routing: routes
+                .iter()
+                .map(|(path, endpoint)| ConfigRouting {
+                    path: (*path).to_string(),
+                    endpoint: (*endpoint).to_string(),
+                    signing_secret: None,
+                    backend_headers: BackendHeaders::default(),
+                    cache_control: None,
+                    fallback_image: None,
+                    fallback_status: None,
+                    path_options: false,
+                    weak_etag: false,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[rstest]
+    #[case::valid(
+        &[("samples/{*path}", "https://example.com/"), ("{*path}", "file:///app/")],
+        Ok(())
+    )]
+    #[case::no_routes(&[], Err(ConfigError::NoRoutes))]
+    #[case::leading_slash(
+        &[("/samples/{*path}", "https://example.com/")],
+        Err(ConfigError::LeadingSlash("/samples/{*path}".into()))
+    )]
+    #[case::missing_capture(
+        &[("samples", "https://example.com/")],
+        Err(ConfigError::MissingCapture("samples".into()))
+    )]
+    #[case::duplicate(
+        &[("a/{*path}", "https://example.com/"), ("a/{*path}", "file:///app/")],
+        Err(ConfigError::DuplicatePath("a/{*path}".into()))
+    )]
+    #[case::overlapping(
+        &[("a/{*path}", "https://example.com/"), ("a/{*rest}", "file:///app/")],
+        Err(ConfigError::OverlappingPaths("a/{*path}".into(), "a/{*rest}".into()))
+    )]
+    #[case::empty_endpoint(
+        &[("a/{*path}", " ")],
+        Err(ConfigError::EmptyEndpoint("a/{*path}".into()))
+    )]
+    #[case::invalid_endpoint(
+        &[("a/{*path}", "example.com")],
+        Err(ConfigError::InvalidEndpoint("a/{*path}".into(), "example.com".into()))
+    )]
+    #[case::missing_s3(
+        &[("a/{*path}", "s3://bucket/")],
+        Err(ConfigError::MissingS3("a/{*path}".into()))
+    )]
+    fn test_validate(#[case] routing: &[(&str, &str)], #[case] expected: Result<(), ConfigError>) {
+        assert_eq!(routes(routing).validate(), expected);
+    }
+
+    #[rstest]
+    #[case::valid(50, Ok(()))]
+    #[case::zero(0, Err(ConfigError::InvalidSaveDataQuality))]
+    fn test_validate_save_data_quality(
+        #[case] quality: i32,
+        #[case] expected: Result<(), ConfigError>,
+    ) {
+        let mut config = routes(&[("a/{*path}", "https://example.com/")]);
+        config.save_data_quality = quality;
+        assert_eq!(config.validate(), expected);
+    }
+
+    #[rstest]
+    #[case::valid(2.0, 0.85, Ok(()))]
+    #[case::zero_factor(2.0, 0.0, Err(ConfigError::InvalidDprQuality(2.0)))]
+    #[case::above_one(2.0, 1.5, Err(ConfigError::InvalidDprQuality(2.0)))]
+    #[case::infinite_dpr(f64::INFINITY, 0.5, Err(ConfigError::InvalidDprQuality(f64::INFINITY)))]
+    fn test_validate_dpr_quality(
+        #[case] dpr: f64,
+        #[case] factor: f64,
+        #[case] expected: Result<(), ConfigError>,
+    ) {
+        let mut config = routes(&[("a/{*path}", "https://example.com/")]);
+        config.dpr_quality = vec![DprQuality { dpr, factor }];
+        assert_eq!(config.validate(), expected);
+    }
+
+    #[test]
+    fn test_preset_deserialize() {
+        let preset: Preset =
+            serde_json::from_value(json!({"w": 150, "fit": "crop", "strip": false})).unwrap();
+        assert_eq!(
+            preset.0,
+            BTreeMap::from([
+                ("fit".to_string(), "crop".to_string()),
+                ("strip".to_string(), "false".to_string()),
+                ("w".to_string(), "150".to_string()),
+            ])
+        );
+        assert!(serde_json::from_value::<Preset>(json!({"w": [150]})).is_err());
+    }
+
+    #[rstest]
+    #[case::valid(&[("w", "150"), ("fit", "crop")], true)]
+    #[case::invalid_value(&[("fit", "sideways")], false)]
+    #[case::nested_preset(&[("preset", "other")], false)]
+    #[case::signature(&[("sig", "abc")], false)]
+    fn test_validate_presets(#[case] params: &[(&str, &str)], #[case] valid: bool) {
+        let mut config = routes(&[("a/{*path}", "https://example.com/")]);
+        let preset = params
+            .iter()
+            .map(|(name, value)| ((*name).to_string(), (*value).to_string()))
+            .collect();
+        config.presets = BTreeMap::from([("thumb".to_string(), Preset(preset))]);
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case::valid("/a/cat.jpg?w=300&fit=crop", true)]
+    #[case::relative("a/cat.jpg?w=300", false)]
+    #[case::absolute("https://example.com/a/cat.jpg", false)]
+    #[case::invalid("/a/cat 1.jpg", false)]
+    fn test_validate_warmup(#[case] entry: &str, #[case] valid: bool) {
+        let mut config = routes(&[("a/{*path}", "https://example.com/")]);
+        config.warmup = vec![entry.to_string()];
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case::http("images.example.com", true)]
+    #[case::case_insensitive("Images.Example.com", true)]
+    #[case::s3_bucket("bucket", true)]
+    #[case::request_host("attacker.example.com", false)]
+    fn test_is_endpoint_host(#[case] host: &str, #[case] expected: bool) {
+        let config = routes(&[
+            ("a/{*path}", "https://images.example.com/a/"),
+            ("b/{*path}", "s3://bucket/"),
+        ]);
+        assert_eq!(config.is_endpoint_host(host), expected);
+    }
+
+    #[rstest]
+    #[case::below(1.0, None)]
+    #[case::exact(2.0, Some(0.85))]
+    #[case::between(2.5, Some(0.85))]
+    #[case::highest(4.0, Some(0.7))]
+    fn test_dpr_quality_factor(#[case] dpr: f64, #[case] expected: Option<f64>) {
+        let config = Config {
+            dpr_quality: vec![
+                DprQuality {
+                    dpr: 3.0,
+                    factor: 0.7,
+                },
+                DprQuality {
+                    dpr: 2.0,
+                    factor: 0.85,
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(config.dpr_quality_factor(dpr), expected);
+    }
+
+    #[rstest]
+    #[case::valid("x-api-key", "secret", Ok(()))]
+    #[case::invalid_name("x api key", "secret", Err(ConfigError::InvalidBackendHeader("a/{*path}".to_string(), "x api key".to_string())))]
+    #[case::invalid_value("x-api-key", "line\nbreak", Err(ConfigError::InvalidBackendHeader("a/{*path}".to_string(), "x-api-key".to_string())))]
+    fn test_validate_backend_headers(
+        #[case] name: &str,
+        #[case] value: &str,
+        #[case] expected: Result<(), ConfigError>,
+    ) {
+        let mut config = routes(&[("a/{*path}", "https://example.com/")]);
+        config.routing[0]
+            .backend_headers
+            .0
+            .insert(name.to_string(), value.to_string());
+        assert_eq!(config.validate(), expected);
+    }
+
+    #[rstest]
+    #[case::unset(None, true)]
+    #[case::no_store(Some("no-store"), true)]
+    #[case::invalid(Some("max-age=60\n"), false)]
+    fn test_validate_cache_control(#[case] cache_control: Option<&str>, #[case] valid: bool) {
+        let mut config = routes(&[("a/{*path}", "https://example.com/")]);
+        config.routing[0].cache_control = cache_control.map(str::to_string);
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[rstest]
+    #[case::unset(None, None, true)]
+    #[case::valid(Some("file:///images/placeholder.png"), Some(200), true)]
+    #[case::relative(Some("placeholder.png"), None, false)]
+    #[case::bad_status(Some("file:///images/placeholder.png"), Some(999), false)]
+    fn test_validate_fallback(
+        #[case] fallback_image: Option<&str>,
+        #[case] fallback_status: Option<u16>,
+        #[case] valid: bool,
+    ) {
+        let mut config = routes(&[("a/{*path}", "https://example.com/")]);
+        config.routing[0].fallback_image = fallback_image.map(str::to_string);
+        config.routing[0].fallback_status = fallback_status;
+        assert_eq!(config.validate().is_ok(), valid);
+    }
+
+    #[test]
+    fn test_backend_headers_redacted() {
+        let headers = BackendHeaders(BTreeMap::from([(
+            "authorization".to_string(),
+            "Bearer secret".to_string(),
+        )]));
+        assert_eq!(
+            format!("{headers:?}"),
+            "{\"authorization\": \"<redacted>\"}"
+        );
+    }
+
+    #[rstest]
+    #[case::valid(Some(82), Ok(()))]
+    #[case::zero(Some(0), Err(ConfigError::InvalidQuality(ImageFormat::Webp)))]
+    #[case::too_high(Some(101), Err(ConfigError::InvalidQuality(ImageFormat::Webp)))]
+    fn test_validate_quality(#[case] webp: Option<i32>, #[case] expected: Result<(), ConfigError>) {
+        let mut config = routes(&[("a/{*path}", "https://example.com/")]);
+        config.default_quality.webp = webp;
+        assert_eq!(config.validate(), expected);
+    }
 }
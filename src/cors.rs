@@ -0,0 +1,96 @@
+use crate::config::CorsConfig;
+use crate::service::Service;
+use axum::extract::{Request, State};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, header};
+use axum::{middleware::Next, response::IntoResponse, response::Response};
+use std::sync::Arc;
+
+/// Add CORS headers for allowed origins and answer preflight requests.
+pub async fn middleware(State(ctx): State<Arc<Service>>, req: Request, next: Next) -> Response {
+    let config = ctx.config();
+    let Some(cors) = &config.cors else {
+        return next.run(req).await;
+    };
+    let origin = req.headers().get(header::ORIGIN).cloned();
+    let allowed = origin
+        .as_ref()
+        .and_then(|origin| allow_origin(cors, origin));
+
+    let preflight = req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    if preflight {
+        let mut headers = HeaderMap::new();
+        if let Some(allowed) = allowed {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static("GET, HEAD, OPTIONS"),
+            );
+            if let Some(request_headers) = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            {
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_HEADERS,
+                    request_headers.clone(),
+                );
+            }
+            if let Some(max_age) = cors.max_age {
+                headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+            }
+        }
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        return (StatusCode::NO_CONTENT, headers).into_response();
+    }
+
+    let mut response = next.run(req).await;
+    let headers = response.headers_mut();
+    if let Some(allowed) = allowed {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allowed);
+        headers.insert(
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            HeaderValue::from_static("Content-Length, Content-Range, X-Request-Id"),
+        );
+    }
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    response
+}
+
+/// The `Access-Control-Allow-Origin` value for a request origin, if it's allowed.
+fn allow_origin(cors: &CorsConfig, origin: &HeaderValue) -> Option<HeaderValue> {
+    if cors.allowed_origins.iter().any(|allowed| allowed == "*") {
+        return Some(HeaderValue::from_static("*"));
+    }
+    let origin_str = origin.to_str().ok()?;
+    cors.allowed_origins
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(origin_str))
+        .then(|| origin.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(&["https://example.com"], "https://example.com", Some("https://example.com"))]
+    #[case(&["https://example.com"], "https://EXAMPLE.com", Some("https://EXAMPLE.com"))]
+    #[case(&["https://example.com"], "https://evil.com", None)]
+    #[case(&["*"], "https://evil.com", Some("*"))]
+    #[case(&[], "https://example.com", None)]
+    fn test_allow_origin(
+        #[case] allowed_origins: &[&str],
+        #[case] origin: &str,
+        #[case] expected: Option<&str>,
+    ) {
+        let cors = CorsConfig {
+            allowed_origins: allowed_origins.iter().map(|s| (*s).to_string()).collect(),
+            max_age: None,
+        };
+        assert_eq!(
+            allow_origin(&cors, &HeaderValue::from_str(origin).unwrap()),
+            expected.map(|value| HeaderValue::from_str(value).unwrap())
+        );
+    }
+}
@@ -1,7 +1,8 @@
 use axum::{
-    http::StatusCode, http::header::InvalidHeaderName, http::header::InvalidHeaderValue,
-    response::IntoResponse, response::Response,
+    http::StatusCode, http::header, http::header::InvalidHeaderName,
+    http::header::InvalidHeaderValue, response::IntoResponse, response::Response,
 };
+use serde_json::json;
 use tracing::error;
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -27,15 +28,67 @@ pub enum Error {
     InvalidSignature,
     #[error("file not found")]
     NotFound,
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("source is not a supported image")]
+    UnsupportedMediaType,
+    #[error("source too large: {0}")]
+    SourceTooLarge(String),
+    #[error("backend unavailable")]
+    CircuitOpen,
+    #[error("backend responded with status {0}")]
+    Upstream(u16),
+    #[error("too many images being processed")]
+    Overloaded,
     #[error("rayon error: {0}")]
     Rayon(String),
 }
 
+impl Error {
+    /// The status and machine readable code for errors caused by the request.
+    /// Server errors return `None` so their details aren't exposed.
+    fn client_error(&self) -> Option<(StatusCode, &'static str)> {
+        match self {
+            Error::NotFound => Some((StatusCode::NOT_FOUND, "not_found")),
+            Error::InvalidSignature => Some((StatusCode::UNAUTHORIZED, "invalid_signature")),
+            Error::BadRequest(_) => Some((StatusCode::BAD_REQUEST, "bad_request")),
+            Error::UnsupportedMediaType => {
+                Some((StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type"))
+            }
+            Error::SourceTooLarge(_) => {
+                Some((StatusCode::UNPROCESSABLE_ENTITY, "source_too_large"))
+            }
+            _ => None,
+        }
+    }
+
+    /// Like `into_response`, but client errors have a JSON body describing
+    /// the error, for clients that send `Accept: application/json`.
+    pub fn into_json_response(self) -> Response {
+        match self.client_error() {
+            Some((status, code)) => (
+                status,
+                [(header::CONTENT_TYPE, "application/json")],
+                json!({"error": code, "message": self.to_string()}).to_string(),
+            )
+                .into_response(),
+            None => self.into_response(),
+        }
+    }
+}
+
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
+        if let Some((status, _)) = self.client_error() {
+            return status.into_response();
+        }
         match self {
-            Error::NotFound => StatusCode::NOT_FOUND.into_response(),
-            Error::InvalidSignature => StatusCode::UNAUTHORIZED.into_response(),
+            Error::CircuitOpen => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+            Error::Upstream(status) => {
+                error!("backend responded with status {}", status);
+                StatusCode::BAD_GATEWAY.into_response()
+            }
+            Error::Overloaded => StatusCode::SERVICE_UNAVAILABLE.into_response(),
             Error::Vips(err, error_buffer) => {
                 error!(error = %err, detail = error_buffer);
                 StatusCode::INTERNAL_SERVER_ERROR.into_response()
@@ -55,3 +108,33 @@ impl IntoResponse for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body(response: Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_into_json_response() {
+        let response = Error::BadRequest("width is too large".into()).into_json_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(&body(response).await).unwrap();
+        assert_eq!(
+            body,
+            json!({"error": "bad_request", "message": "bad request: width is too large"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_into_json_response_server_error() {
+        let response = Error::Io("disk full".into()).into_json_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body(response).await.is_empty());
+    }
+}
@@ -1,6 +1,7 @@
-use axum::http::{HeaderMap, header};
+use axum::http::{HeaderMap, HeaderName, header};
 use ipnet::IpNet;
-use std::{net::IpAddr, str::FromStr};
+use opentelemetry::propagation::Extractor;
+use std::{net::IpAddr, ops::Range, str::FromStr};
 
 /// Extension trait for `HeaderMap`.
 pub trait HeaderMapExt {
@@ -10,6 +11,16 @@ pub trait HeaderMapExt {
     fn get_referrer(&self) -> Option<String>;
     /// Return the client IP address from the 'x-forwarded-for' header if present
     fn get_x_forwarded_for(&self, trusted_proxies: &[IpNet]) -> Option<String>;
+    /// Return the device pixel ratio from the 'dpr' client hint if present and valid
+    fn get_dpr(&self) -> Option<f64>;
+    /// Whether the client asked for reduced data usage with 'save-data: on'
+    fn get_save_data(&self) -> bool;
+    /// Whether the 'accept' header lists the media type
+    fn accepts(&self, media_type: &str) -> bool;
+    /// Whether the 'if-none-match' header lists the entity tag, using weak comparison
+    fn if_none_match(&self, etag: &str) -> bool;
+    /// Return the byte range from the 'range' header if present, for a body of `len` bytes
+    fn get_byte_range(&self, len: usize) -> Option<Result<Range<usize>, RangeNotSatisfiable>>;
 }
 
 impl HeaderMapExt for HeaderMap {
@@ -38,6 +49,72 @@ impl HeaderMapExt for HeaderMap {
             .or(x_forwarded_for.0.first())
             .map(std::string::ToString::to_string)
     }
+
+    fn get_dpr(&self) -> Option<f64> {
+        let dpr = self.get("dpr")?.to_str().ok()?.trim().parse::<f64>().ok()?;
+        (dpr.is_finite() && dpr > 0.0).then_some(dpr)
+    }
+
+    fn get_save_data(&self) -> bool {
+        self.get("save-data")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("on"))
+    }
+
+    fn accepts(&self, media_type: &str) -> bool {
+        self.get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|item| {
+                item.split(';')
+                    .next()
+                    .is_some_and(|item| item.trim().eq_ignore_ascii_case(media_type))
+            })
+    }
+
+    fn if_none_match(&self, etag: &str) -> bool {
+        let etag = etag.trim_start_matches("W/");
+        self.get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    }
+
+    fn get_byte_range(&self, len: usize) -> Option<Result<Range<usize>, RangeNotSatisfiable>> {
+        let value = self.get(header::RANGE)?;
+        Some(
+            value
+                .to_str()
+                .map_err(|_| RangeNotSatisfiable)
+                .and_then(|value| parse_byte_range(value, len)),
+        )
+    }
+}
+
+/// The 'range' header was malformed or outside of the body
+#[derive(Debug, PartialEq, Eq)]
+pub struct RangeNotSatisfiable;
+
+/// Parse a single `bytes=start-end`, `bytes=start-` or `bytes=-suffix` range
+fn parse_byte_range(value: &str, len: usize) -> Result<Range<usize>, RangeNotSatisfiable> {
+    let (start, end) = value
+        .strip_prefix("bytes=")
+        .and_then(|range| range.trim().split_once('-'))
+        .ok_or(RangeNotSatisfiable)?;
+    let parse = |value: &str| value.parse::<usize>().map_err(|_| RangeNotSatisfiable);
+
+    let range = match (start, end) {
+        ("", suffix) => len.saturating_sub(parse(suffix)?)..len,
+        (start, "") => parse(start)?..len,
+        (start, end) => parse(start)?..parse(end)?.saturating_add(1).min(len),
+    };
+    if range.start >= range.end {
+        return Err(RangeNotSatisfiable);
+    }
+    Ok(range)
 }
 
 #[derive(Debug)]
@@ -59,9 +136,96 @@ impl FromStr for XForwardedForHeader {
     }
 }
 
+/// Reads propagated trace context, such as `traceparent`, from request headers.
+pub struct HeaderExtractor<'a>(pub &'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("2", Some(2.0))]
+    #[case(" 1.5 ", Some(1.5))]
+    #[case("0", None)]
+    #[case("-1", None)]
+    #[case("inf", None)]
+    #[case("retina", None)]
+    fn test_get_dpr(#[case] value: &str, #[case] expected: Option<f64>) {
+        let mut headers = HeaderMap::new();
+        headers.insert("dpr", value.parse().unwrap());
+        assert_eq!(headers.get_dpr(), expected);
+    }
+
+    #[rstest]
+    #[case(Some("on"), true)]
+    #[case(Some("On"), true)]
+    #[case(Some("off"), false)]
+    #[case(None, false)]
+    fn test_get_save_data(#[case] value: Option<&str>, #[case] expected: bool) {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = value {
+            headers.insert("save-data", value.parse().unwrap());
+        }
+        assert_eq!(headers.get_save_data(), expected);
+    }
+
+    #[rstest]
+    #[case("image/avif,image/webp,*/*;q=0.8", true)]
+    #[case("image/webp;q=0.9", true)]
+    #[case("image/png,*/*", false)]
+    fn test_accepts(#[case] accept: &str, #[case] expected: bool) {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, accept.parse().unwrap());
+        assert_eq!(headers.accepts("image/webp"), expected);
+    }
+
+    #[rstest]
+    #[case::weak("W/\"abc\"", true)]
+    #[case::strong("\"abc\"", true)]
+    #[case::list("\"xyz\", W/\"abc\"", true)]
+    #[case::any("*", true)]
+    #[case::other("W/\"xyz\"", false)]
+    fn test_if_none_match(#[case] value: &str, #[case] expected: bool) {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, value.parse().unwrap());
+        assert_eq!(headers.if_none_match("W/\"abc\""), expected);
+    }
+
+    #[test]
+    fn test_extract_trace_context() {
+        use opentelemetry::propagation::TextMapPropagator;
+        use opentelemetry::trace::TraceContextExt;
+        use opentelemetry_sdk::propagation::TraceContextPropagator;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+
+        let cx = TraceContextPropagator::new().extract(&HeaderExtractor(&headers));
+        let span = cx.span();
+        let span_context = span.span_context();
+        assert!(span_context.is_remote());
+        assert_eq!(
+            span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(span_context.span_id().to_string(), "00f067aa0ba902b7");
+    }
 
     #[test]
     fn test_x_forwarded_for_correct_ip() {
@@ -123,6 +287,26 @@ mod tests {
         );
     }
 
+    #[rstest]
+    #[case::full("bytes=0-9", Ok(0..10))]
+    #[case::middle("bytes=2-4", Ok(2..5))]
+    #[case::open_ended("bytes=5-", Ok(5..10))]
+    #[case::suffix("bytes=-3", Ok(7..10))]
+    #[case::end_past_length("bytes=5-100", Ok(5..10))]
+    #[case::suffix_past_length("bytes=-100", Ok(0..10))]
+    #[case::start_past_length("bytes=10-", Err(RangeNotSatisfiable))]
+    #[case::reversed("bytes=5-2", Err(RangeNotSatisfiable))]
+    #[case::multiple("bytes=0-1,4-5", Err(RangeNotSatisfiable))]
+    #[case::wrong_unit("items=0-1", Err(RangeNotSatisfiable))]
+    fn test_byte_range(
+        #[case] value: &str,
+        #[case] expected: Result<Range<usize>, RangeNotSatisfiable>,
+    ) {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, value.parse().unwrap());
+        assert_eq!(headers.get_byte_range(10), Some(expected));
+    }
+
     #[test]
     fn test_x_forwarded_for_no_header() {
         let trusted_proxies = vec![];
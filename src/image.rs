@@ -13,11 +13,19 @@ use opentelemetry::trace::Tracer;
 use std::mem::discriminant;
 use tracing::error;
 
+#[derive(Clone)]
 pub struct Image {
     pub bytes: Vec<u8>,
     pub content_type: options::ImageFormat,
 }
 
+/// Additional source images fetched from the backend for compositing
+#[derive(Default)]
+pub struct Overlays {
+    pub watermark: Option<Vec<u8>>,
+    pub overlay: Option<Vec<u8>>,
+}
+
 pub fn flatten(
     image: &VipsImage,
     colour: &options::Colour,
@@ -39,9 +47,9 @@ fn find_trim(
     options: &options::ImageOptions,
 ) -> VipsResult<(i32, i32, i32, i32)> {
     let mut opts = ops::FindTrimOptions {
-        threshold: 40.0,
+        threshold: options.trim_threshold.unwrap_or(40.0),
         background: vec![255.0, 255.0, 255.0],
-        line_art: false,
+        line_art: options.trim_lineart.unwrap_or(false),
     };
 
     if let Some(colour) = &options.trim_colour {
@@ -101,11 +109,16 @@ fn sharpen(
     let mut span = tracer("shrinkray").start_with_context("sharpen", cx);
     let percentage = options.sharpen.unwrap_or(Percentage(1));
     // min: 0.000001, max: 10, default: 0.5
-    let sigma = percent_to_value(percentage.0, 0.000_001, 10.0);
-    let opts = ops::SharpenOptions {
+    let sigma = options
+        .sharpen_radius
+        .unwrap_or_else(|| percent_to_value(percentage.0, 0.000_001, 10.0));
+    let mut opts = ops::SharpenOptions {
         sigma,
         ..Default::default()
     };
+    if let Some(amount) = options.sharpen_amount {
+        opts.m_2 = amount;
+    }
     let result = ops::sharpen_with_opts(image, &opts);
     <dyn ObjectSafeSpan>::end(&mut span);
     result
@@ -124,11 +137,197 @@ fn blur(
         min_ampl: 0.001, // min: 0.001, max: 1, default: 0.2
         precision: ops::Precision::Approximate,
     };
-    let result = ops::gaussblur_with_opts(image, sigma, &opts);
+    let result = with_premultiplied(image, |image| ops::gaussblur_with_opts(image, sigma, &opts));
+    <dyn ObjectSafeSpan>::end(&mut span);
+    result
+}
+
+/// Run a step that mixes neighbouring pixels on premultiplied colours, so the
+/// colour of transparent pixels doesn't bleed into the edges of visible ones.
+/// `thumbnail` and `composite` already do this themselves.
+fn with_premultiplied(
+    image: &VipsImage,
+    step: impl FnOnce(&VipsImage) -> VipsResult<VipsImage>,
+) -> VipsResult<VipsImage> {
+    if !image.image_hasalpha() {
+        return step(image);
+    }
+    let format = image.get_format()?;
+    let result = step(&ops::premultiply(image)?)?;
+    ops::cast(&ops::unpremultiply(&result)?, format)
+}
+
+/// Round each colour channel to one of `levels` evenly spaced values
+fn posterize(image: &VipsImage, levels: i32) -> VipsResult<VipsImage> {
+    let steps = f64::from(levels - 1);
+    let white = white_point(image)?;
+    let bands = usize::try_from(image.get_bands()).unwrap_or(1);
+    let mut multiply = vec![steps / white; bands];
+    if image.image_hasalpha() {
+        multiply[bands - 1] = 1.0;
+    }
+    let scaled = ops::linear(image, &mut multiply, &mut vec![0.0; bands])?;
+    let rounded = ops::round(&scaled, ops::OperationRound::Rint)?;
+    ops::cast(
+        &linear_colour_bands(&rounded, white / steps, 0.0)?,
+        image.get_format()?,
+    )
+}
+
+/// Convert to black and white by comparing the luminance with a cutoff
+fn threshold(image: &VipsImage, cutoff: options::Percentage) -> VipsResult<VipsImage> {
+    let grey = ops::colourspace(image, ops::Interpretation::BW)?;
+    let luminance = ops::extract_band(&grey, 0)?;
+    let cutoff = percent_to_value(cutoff.0, 0.0, 255.0);
+    let mask = ops::relational_const(&luminance, ops::OperationRelational::Moreeq, &mut [cutoff])?;
+    if grey.image_hasalpha() {
+        ops::bandjoin(&mut [mask, ops::extract_band(&grey, 1)?])
+    } else {
+        Ok(mask)
+    }
+}
+
+/// Downsample and scale back up with nearest-neighbour to produce square blocks
+fn pixelate(image: &VipsImage, block: i32, cx: &TraceContext) -> VipsResult<VipsImage> {
+    let mut span = tracer("shrinkray").start_with_context("pixelate", cx);
+    // libvips-rs always passes vscale, so it must be set for both passes
+    let down = ops::ResizeOptions {
+        kernel: ops::Kernel::Nearest,
+        vscale: 1.0 / f64::from(block),
+        ..Default::default()
+    };
+    let result = ops::resize_with_opts(image, 1.0 / f64::from(block), &down).and_then(|small| {
+        // Scale each axis separately so the result has the original dimensions
+        let up = ops::ResizeOptions {
+            kernel: ops::Kernel::Nearest,
+            vscale: f64::from(image.get_height()) / f64::from(small.get_height()),
+            ..Default::default()
+        };
+        let scale = f64::from(image.get_width()) / f64::from(small.get_width());
+        ops::resize_with_opts(&small, scale, &up)
+    });
+    <dyn ObjectSafeSpan>::end(&mut span);
+    result
+}
+
+/// Apply a per-band linear transform, leaving the alpha channel untouched and
+/// keeping the band format so 16-bit images aren't reduced to 8 bits
+fn linear_colour_bands(image: &VipsImage, multiply: f64, add: f64) -> VipsResult<VipsImage> {
+    let bands = usize::try_from(image.get_bands()).unwrap_or(1);
+    let mut multiply = vec![multiply; bands];
+    let mut addition = vec![add; bands];
+    if image.image_hasalpha() {
+        multiply[bands - 1] = 1.0;
+        addition[bands - 1] = 0.0;
+    }
+    let format = image.get_format()?;
+    ops::cast(&ops::linear(image, &mut multiply, &mut addition)?, format)
+}
+
+/// The value of a fully saturated channel, which depends on the bit depth
+fn white_point(image: &VipsImage) -> VipsResult<f64> {
+    Ok(match image.get_format()? {
+        ops::BandFormat::Ushort => 65535.0,
+        _ => 255.0,
+    })
+}
+
+/// Stretch each colour channel so its darkest and lightest values span the
+/// full range of the band format
+fn autolevel(image: &VipsImage, cx: &TraceContext) -> VipsResult<VipsImage> {
+    let mut span = tracer("shrinkray").start_with_context("autolevel", cx);
+    let result = ops::stats(image).and_then(|stats| {
+        let white = white_point(image)?;
+        let bands = image.get_bands();
+        let colour_bands = if image.image_hasalpha() {
+            bands - 1
+        } else {
+            bands
+        };
+        let mut multiply = Vec::new();
+        let mut addition = Vec::new();
+        for band in 0..bands {
+            // Row 0 holds the statistics for all bands together
+            let min = ops::getpoint(&stats, 0, band + 1)?[0];
+            let max = ops::getpoint(&stats, 1, band + 1)?[0];
+            if band < colour_bands && max > min {
+                let scale = white / (max - min);
+                multiply.push(scale);
+                addition.push(-min * scale);
+            } else {
+                multiply.push(1.0);
+                addition.push(0.0);
+            }
+        }
+        let format = image.get_format()?;
+        ops::cast(&ops::linear(image, &mut multiply, &mut addition)?, format)
+    });
+    <dyn ObjectSafeSpan>::end(&mut span);
+    result
+}
+
+fn tone(
+    image: &VipsImage,
+    options: &options::ImageOptions,
+    cx: &TraceContext,
+) -> VipsResult<VipsImage> {
+    let mut span = tracer("shrinkray").start_with_context("tone", cx);
+    // Contrast scales around mid-grey, brightness shifts by up to the full range
+    let result = white_point(image).and_then(|white| {
+        let contrast = 1.0 + options.contrast.as_ref().map_or(0.0, f64::from) / 100.0;
+        let brightness = options.brightness.as_ref().map_or(0.0, f64::from) / 100.0 * white;
+        let add = (white + 1.0) / 2.0 * (1.0 - contrast) + brightness;
+        linear_colour_bands(image, contrast, add)
+    });
     <dyn ObjectSafeSpan>::end(&mut span);
     result
 }
 
+/// Scale the chroma and rotate the hue of an image in LCh space
+fn adjust_lch(image: &VipsImage, chroma: f64, hue: f64) -> VipsResult<VipsImage> {
+    let lch = ops::colourspace(image, ops::Interpretation::Lch)?;
+    let bands = usize::try_from(lch.get_bands()).unwrap_or(3);
+    let mut multiply = vec![1.0; bands];
+    let mut addition = vec![0.0; bands];
+    multiply[1] = chroma;
+    addition[2] = hue;
+    let lch = ops::linear(&lch, &mut multiply, &mut addition)?;
+    ops::colourspace(&lch, ops::Interpretation::Srgb)
+}
+
+/// Move the a and b channels in Lab space towards those of the colour
+fn tint(
+    image: &VipsImage,
+    colour: &options::Colour,
+    opacity: options::Percentage,
+) -> VipsResult<VipsImage> {
+    let image = if colourspace_is_srgb(image)? {
+        image.clone()
+    } else {
+        ops::colourspace(image, ops::Interpretation::Srgb)?
+    };
+
+    // Find the colour in Lab space from a single sRGB pixel
+    let mut pixel: Vec<f64> = colour.into();
+    if image.image_hasalpha() {
+        pixel.push(255.0);
+    }
+    let fill = VipsImage::new_from_image(&ops::extract_area(&image, 0, 0, 1, 1)?, &pixel)?;
+    let target = ops::getpoint(&ops::colourspace(&fill, ops::Interpretation::Lab)?, 0, 0)?;
+
+    let lab = ops::colourspace(&image, ops::Interpretation::Lab)?;
+    let bands = usize::try_from(lab.get_bands()).unwrap_or(3);
+    let strength = f64::from(opacity.0.clamp(0, 100)) / 100.0;
+    let mut multiply = vec![1.0; bands];
+    let mut addition = vec![0.0; bands];
+    for channel in 1..3 {
+        multiply[channel] = 1.0 - strength;
+        addition[channel] = target[channel] * strength;
+    }
+    let lab = ops::linear(&lab, &mut multiply, &mut addition)?;
+    ops::colourspace(&lab, ops::Interpretation::Srgb)
+}
+
 fn colourspace_is_srgb(image: &VipsImage) -> VipsResult<bool> {
     let interp = image.get_interpretation()?;
     let srgb = ops::Interpretation::Srgb;
@@ -146,49 +345,388 @@ fn colourspace(image: &VipsImage, cx: &TraceContext) -> VipsResult<VipsImage> {
     result
 }
 
+/// How far outside the 0 to 1 range of linear sRGB a primary may land, through
+/// rounding in the colour transforms, before its profile counts as wide-gamut
+const GAMUT_TOLERANCE: f64 = 0.02;
+
+/// Check whether the embedded profile reaches colours outside sRGB, by passing a
+/// single pixel of each of its primaries into linear sRGB, where such colours
+/// fall outside the 0 to 1 range. Sources without a profile are sRGB.
+fn wider_than_srgb(bytes: &[u8]) -> VipsResult<bool> {
+    let image = VipsImage::new_from_buffer(bytes, "[access=VIPS_ACCESS_SEQUENTIAL]")?;
+    let interp = image.get_interpretation()?;
+    let rgb = [ops::Interpretation::Srgb, ops::Interpretation::Rgb16];
+    if image.get_bands() < 3
+        || !rgb
+            .iter()
+            .any(|rgb| discriminant(rgb) == discriminant(&interp))
+    {
+        return Ok(false);
+    }
+
+    let pixel = ops::extract_band_with_opts(
+        &ops::extract_area(&image, 0, 0, 1, 1)?,
+        0,
+        &ops::ExtractBandOptions { n: 3 },
+    )?;
+    let white = white_point(&image)?;
+    let opts = ops::IccImportOptions {
+        embedded: true,
+        input_profile: "sRGB".to_string(),
+        ..Default::default()
+    };
+    for primary in 0..3 {
+        let mut value = [0.0; 3];
+        value[primary] = white;
+        let probe = ops::cast(
+            &ops::linear(&pixel, &mut [0.0; 3], &mut value)?,
+            image.get_format()?,
+        )?;
+        let linear = ops::colourspace(
+            &ops::icc_import_with_opts(&probe, &opts)?,
+            ops::Interpretation::Scrgb,
+        )?;
+        if ops::min(&linear)? < -GAMUT_TOLERANCE || ops::max(&linear)? > 1.0 + GAMUT_TOLERANCE {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Convert to the output profile, using the embedded profile if present or assuming sRGB.
+/// The result stays in sRGB interpretation so later steps can treat it as RGB.
+fn wide_gamut(
+    image: &VipsImage,
+    options: &options::ImageOptions,
+    cx: &TraceContext,
+) -> VipsResult<VipsImage> {
+    let mut span = tracer("shrinkray").start_with_context("wide_gamut", cx);
+    let opts = ops::IccTransformOptions {
+        embedded: true,
+        input_profile: "sRGB".to_string(),
+        ..Default::default()
+    };
+    let result = ops::icc_transform_with_opts(image, options.output_profile(), &opts);
+    <dyn ObjectSafeSpan>::end(&mut span);
+    result
+}
+
 /// Check if the image needs rotation based on EXIF data
+/// Orientations 2 to 8 are rotations, mirrors or both, which `autorot` undoes.
 fn needs_rotation(buffer: &[u8]) -> bool {
     match rexif::parse_buffer_quiet(buffer).0 {
         Ok(data) => data.entries.into_iter().any(|e| {
             e.tag == rexif::ExifTag::Orientation
-                && e.value.to_i64(0).is_some()
-                && e.value.to_i64(0).unwrap() != 0
-                && e.value.to_i64(0).unwrap() != 1
+                && e.value
+                    .to_i64(0)
+                    .is_some_and(|orientation| (2..=8).contains(&orientation))
         }),
         Err(_) => false,
     }
 }
 
-fn load(bytes: &[u8], random_access: bool, cx: &TraceContext) -> VipsResult<VipsImage> {
+/// The loaders that rasterise at a resolution chosen by the request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Loader {
+    Pdf,
+    Svg,
+    Raster,
+}
+
+/// What's needed from the source before decoding it, read from its header
+/// once without pulling any pixels
+#[derive(Clone, Copy, Debug)]
+pub struct SourceHeader {
+    pub width: i32,
+    pub page_height: i32,
+    pub pages: i32,
+    pub loader: Loader,
+}
+
+impl SourceHeader {
+    /// Fails when no libvips loader recognises the source
+    pub fn read(bytes: &[u8]) -> VipsResult<Self> {
+        let image = VipsImage::new_from_buffer(bytes, "")?;
+        let loader = if is_pdf(bytes) {
+            Loader::Pdf
+        } else if is_svg(bytes) {
+            Loader::Svg
+        } else {
+            Loader::Raster
+        };
+        Ok(Self {
+            width: image.get_width(),
+            page_height: image.get_page_height(),
+            pages: image.get_n_pages().max(1),
+            loader,
+        })
+    }
+
+    /// Whether every frame is loaded to keep the animation
+    pub fn animated(&self, options: &options::ImageOptions) -> bool {
+        options.preserves_animation() && self.loader != Loader::Pdf && self.pages > 1
+    }
+
+    /// The number of pixels `load` decodes. The header is read at 72 dpi, but
+    /// documents and vectors are rasterised at the requested resolution, and
+    /// only one page or frame is read unless the animation is kept.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn decoded_pixels(&self, options: &options::ImageOptions) -> u64 {
+        let dpi = match self.loader {
+            Loader::Pdf => options.dpi,
+            Loader::Svg => options.density,
+            Loader::Raster => None,
+        };
+        let scale = f64::from(dpi.unwrap_or(72)) / 72.0;
+        let side = |length: i32| (f64::from(length.max(0)) * scale).ceil() as u64;
+        let pages = if self.animated(options) {
+            u64::from(self.pages.unsigned_abs())
+        } else {
+            1
+        };
+        side(self.width) * side(self.page_height) * pages
+    }
+}
+
+pub fn is_svg(bytes: &[u8]) -> bool {
+    // The root element may follow an XML declaration, comments or a doctype
+    let head = &bytes[..bytes.len().min(1024)];
+    head.trim_ascii_start().starts_with(b"<") && head.windows(4).any(|tag| tag == b"<svg")
+}
+
+/// The format of an image from its magic bytes, for the formats that can also
+/// be written. Other sources such as TIFF, PDF or SVG return `None`.
+pub fn detect_format(bytes: &[u8]) -> Option<options::ImageFormat> {
+    match bytes {
+        [0xff, 0xd8, 0xff, ..] => Some(options::ImageFormat::Jpeg),
+        [0x89, b'P', b'N', b'G', ..] => Some(options::ImageFormat::Png),
+        [b'G', b'I', b'F', b'8', ..] => Some(options::ImageFormat::Gif),
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'W',
+            b'E',
+            b'B',
+            b'P',
+            ..,
+        ] => Some(options::ImageFormat::Webp),
+        [
+            _,
+            _,
+            _,
+            _,
+            b'f',
+            b't',
+            b'y',
+            b'p',
+            b'a',
+            b'v',
+            b'i',
+            b'f',
+            ..,
+        ] => Some(options::ImageFormat::Avif),
+        _ => None,
+    }
+}
+
+/// The name of the source format, from its magic bytes
+fn source_format(bytes: &[u8]) -> Option<&'static str> {
+    if let Some(format) = detect_format(bytes) {
+        return Some(format.into());
+    }
+    match bytes {
+        [
+            _,
+            _,
+            _,
+            _,
+            b'f',
+            b't',
+            b'y',
+            b'p',
+            b'h',
+            b'e',
+            b'i',
+            b'c',
+            ..,
+        ] => Some("heic"),
+        [b'I', b'I', 0x2a, 0x00, ..] | [b'M', b'M', 0x00, 0x2a, ..] => Some("tiff"),
+        _ if is_pdf(bytes) => Some("pdf"),
+        _ if is_svg(bytes) => Some("svg"),
+        _ => None,
+    }
+}
+
+/// The media type of the source, from its magic bytes, for serving it unchanged
+pub fn source_content_type(bytes: &[u8]) -> &'static str {
+    if let Some(format) = detect_format(bytes) {
+        return format.content_type();
+    }
+    match source_format(bytes) {
+        Some("heic") => "image/heic",
+        Some("tiff") => "image/tiff",
+        Some("pdf") => "application/pdf",
+        Some("svg") => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Describe the source image without decoding its pixels
+fn metadata(bytes: &[u8], cx: &TraceContext) -> VipsResult<Image> {
+    let mut span = tracer("shrinkray").start_with_context("metadata", cx);
+    let result = VipsImage::new_from_buffer(bytes, "").map(|image| {
+        let interpretation = image
+            .get_interpretation()
+            .map(|interpretation| format!("{interpretation:?}").to_lowercase())
+            .ok();
+        let json = serde_json::json!({
+            "width": image.get_width(),
+            "height": image.get_page_height(),
+            "pages": image.get_n_pages(),
+            "format": source_format(bytes),
+            "interpretation": interpretation,
+            "has_alpha": image.image_hasalpha(),
+            "orientation": image.get_orientation(),
+        });
+        Image {
+            bytes: json.to_string().into_bytes(),
+            content_type: options::ImageFormat::Meta,
+        }
+    });
+    <dyn ObjectSafeSpan>::end(&mut span);
+    result
+}
+
+pub fn is_pdf(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"%PDF-")
+}
+
+fn load(
+    bytes: &[u8],
+    options: &options::ImageOptions,
+    random_access: bool,
+    animated: bool,
+    cx: &TraceContext,
+) -> VipsResult<VipsImage> {
     let mut span = tracer("shrinkray").start_with_context("load", cx);
 
     // If rotation is needed, load the image with random access
-    let result = if random_access {
-        VipsImage::new_from_buffer(bytes, "[access=VIPS_ACCESS_RANDOM]")
+    let mut load_options = if random_access {
+        String::from("access=VIPS_ACCESS_RANDOM")
     } else {
-        VipsImage::new_from_buffer(bytes, "[access=VIPS_ACCESS_SEQUENTIAL]")
+        String::from("access=VIPS_ACCESS_SEQUENTIAL")
     };
+    // Load every frame of an animation as a vertical strip of pages
+    if animated {
+        load_options.push_str(",n=-1");
+    }
+    // Render the selected page of a document
+    if is_pdf(bytes) {
+        load_options.push_str(&format!(
+            ",page={},dpi={}",
+            options.page.unwrap_or(0),
+            options.dpi.unwrap_or(72)
+        ));
+    } else if let Some(density) = options.density
+        && is_svg(bytes)
+    {
+        // Rasterise vectors at the requested density
+        load_options.push_str(&format!(",dpi={density}"));
+    } else if let Some(frame) = options.frame {
+        // Extract a single frame of an animation
+        load_options.push_str(&format!(",page={frame}"));
+    }
+    let result = VipsImage::new_from_buffer(bytes, &format!("[{load_options}]"));
     <dyn ObjectSafeSpan>::end(&mut span);
     result
 }
 
+/// A 1x1 GIF decoded by the deep health check
+const HEALTH_CHECK_IMAGE: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\xff\xff\xff\x00\x00\x00!\xf9\x04\x01\x00\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00;";
+
+/// Run a tiny embedded image through the processing pipeline to check that
+/// libvips can still decode and encode images.
+pub fn health_check(config: &Config, cx: &TraceContext) -> VipsResult<()> {
+    let mut options = options::ImageOptions {
+        width: Some(1),
+        format: Some(options::ImageFormat::Png),
+        ..Default::default()
+    };
+    process_image(
+        HEALTH_CHECK_IMAGE,
+        &Overlays::default(),
+        &mut options,
+        config,
+        cx,
+    )
+    .map(|_| ())
+}
+
 pub fn process_image(
     bytes: &[u8],
+    overlays: &Overlays,
+    options: &mut options::ImageOptions,
+    config: &Config,
+    cx: &TraceContext,
+) -> VipsResult<Image> {
+    let header = SourceHeader::read(bytes)?;
+    process_source(bytes, &header, overlays, options, config, cx)
+}
+
+/// Process a source whose header has already been read and checked
+pub fn process_source(
+    bytes: &[u8],
+    header: &SourceHeader,
+    overlays: &Overlays,
     options: &mut options::ImageOptions,
     config: &Config,
     cx: &TraceContext,
 ) -> VipsResult<Image> {
     let tracer = tracer("shrinkray");
 
+    if options.output_format() == options::ImageFormat::Meta {
+        return metadata(bytes, cx);
+    }
+
     let rotation = options.rotate.is_some() || needs_rotation(bytes);
-    let random_access = rotation || options.trim.is_some();
+    let mirror = options.flip == Some(true) || options.flop == Some(true);
+    let random_access = rotation || mirror || options.trim.is_some();
+
+    let animated = header.animated(options);
 
-    let mut image = load(bytes, random_access, cx)?;
+    let mut image = load(bytes, options, random_access, animated, cx)?;
+
+    // Wide-gamut colour profile, kept only when sRGB would clip the source
+    if options.profile == Some(options::Profile::Keep) {
+        if wider_than_srgb(bytes)? {
+            image = wide_gamut(&image, options, cx)?;
+        } else {
+            options.profile = Some(options::Profile::Srgb);
+        }
+    }
 
     // Rotation
     if rotation {
         image = rotate(&image, options, cx)?;
     }
+    if mirror {
+        image = flip(&image, options, cx)?;
+    }
+
+    // Extract an exact rectangle
+    if let Some(rect) = options.crop.and_then(options::Crop::rect)
+        && let Some(rect) = rect.clamp(image.get_width(), image.get_height())
+    {
+        let mut span = tracer.start_with_context("crop", cx);
+        image = ops::extract_area(&image, rect.x, rect.y, rect.width, rect.height)?;
+        <dyn ObjectSafeSpan>::end(&mut span);
+    }
 
     // // Trim whitespace
     if options.trim.is_some() {
@@ -200,10 +738,14 @@ pub fn process_image(
         image = flatten(&image, background, cx)?;
     }
 
+    // Target dimensions for padding, before they are replaced by the fitted size
+    let pad_dimensions = options.pad_dimensions();
+
     // Resize
     if options.width.is_some() || options.height.is_some() {
+        // Animations are resized per frame
         let image_width = image.get_width();
-        let image_height = image.get_height();
+        let image_height = image.get_page_height();
 
         // Calculate crop dimensions
         options::calculate_dimensions(options, image_width, image_height);
@@ -211,16 +753,43 @@ pub fn process_image(
         image = resize(&image, options, image_width, image_height, cx)?;
     }
 
+    // Pad to the exact dimensions
+    if let Some(dimensions) = pad_dimensions {
+        image = pad(&image, dimensions, options, cx)?;
+    }
+
     // Sharpen
-    if options.sharpen.is_some() {
+    if options.sharpens() {
         image = sharpen(&image, options, cx)?;
     }
 
     // Blur
-    if options.blur.is_some() {
+    if options.blur.is_some_and(|blur| blur.0 > 0) {
         image = blur(&image, options, cx)?;
     }
 
+    // Auto levels
+    if options.autolevel == Some(true) {
+        image = autolevel(&image, cx)?;
+    }
+
+    // Brightness and contrast
+    if options.brightness.is_some() || options.contrast.is_some() {
+        image = tone(&image, options, cx)?;
+    }
+
+    // Saturation and hue
+    if let Some(saturation) = &options.saturation {
+        let mut span = tracer.start_with_context("saturation", cx);
+        image = adjust_lch(&image, f64::from(saturation) / 100.0, 0.0)?;
+        <dyn ObjectSafeSpan>::end(&mut span);
+    }
+    if let Some(hue) = options.hue {
+        let mut span = tracer.start_with_context("hue", cx);
+        image = adjust_lch(&image, 1.0, f64::from(hue))?;
+        <dyn ObjectSafeSpan>::end(&mut span);
+    }
+
     // Filters
     if options.kodachrome.is_some() {
         let mut span = tracer.start_with_context("kodachrome", cx);
@@ -252,37 +821,141 @@ pub fn process_image(
         image = apply_style(&image, MONOCHROME, options.monochrome)?;
         <dyn ObjectSafeSpan>::end(&mut span);
     }
+    if options.greyscale == Some(true) {
+        let mut span = tracer.start_with_context("greyscale", cx);
+        // Converted back to sRGB before output
+        image = ops::colourspace(&image, ops::Interpretation::BW)?;
+        <dyn ObjectSafeSpan>::end(&mut span);
+    }
+    if let Some(colour) = &options.tint {
+        let mut span = tracer.start_with_context("tint", cx);
+        let opacity = options.tint_opacity.unwrap_or(options::Percentage(100));
+        image = tint(&image, colour, opacity)?;
+        <dyn ObjectSafeSpan>::end(&mut span);
+    }
+    // Negate after the styles so they're applied to the original colours
+    if options.negate == Some(true) {
+        let mut span = tracer.start_with_context("negate", cx);
+        image = linear_colour_bands(&image, -1.0, white_point(&image)?)?;
+        <dyn ObjectSafeSpan>::end(&mut span);
+    }
+    if let Some(levels) = options.posterize {
+        let mut span = tracer.start_with_context("posterize", cx);
+        image = posterize(&image, levels)?;
+        <dyn ObjectSafeSpan>::end(&mut span);
+    }
+    if let Some(cutoff) = options.threshold {
+        let mut span = tracer.start_with_context("threshold", cx);
+        image = threshold(&image, cutoff)?;
+        <dyn ObjectSafeSpan>::end(&mut span);
+    }
+    if let Some(block) = options.pixelate {
+        image = pixelate(&image, block, cx)?;
+    }
 
     // sRGB conversion
     if !colourspace_is_srgb(&image)? {
         image = colourspace(&image, cx)?;
     }
 
+    // Vignette, matching the final dimensions
+    if let Some(strength) = options.vignette {
+        let mut span = tracer.start_with_context("vignette", cx);
+        image = vignette(&image, strength)?;
+        <dyn ObjectSafeSpan>::end(&mut span);
+    }
+
+    // Watermark
+    if let Some(watermark) = &overlays.watermark {
+        image = apply_watermark(&image, watermark, options, cx)?;
+    }
+
+    // Overlay
+    if let Some(overlay) = &overlays.overlay {
+        image = apply_overlay(&image, overlay, options, cx)?;
+    }
+
+    // Text
+    if let Some(text) = &options.text {
+        let mut span = tracer.start_with_context("text", cx);
+        image = render_text(&image, text, options)?;
+        <dyn ObjectSafeSpan>::end(&mut span);
+    }
+
+    // Border
+    if let Some(border) = options.border {
+        image = add_border(&image, border, options, cx)?;
+    }
+
+    // Rounded corners
+    if let Some(radius) = options.radius {
+        let mut span = tracer.start_with_context("radius", cx);
+        image = round_corners(&image, radius)?;
+        <dyn ObjectSafeSpan>::end(&mut span);
+    }
+
     // Output the image
     output(&image, options, config, cx)
 }
 
+#[allow(clippy::cast_possible_truncation)]
 fn output(
     image: &VipsImage,
     options: &mut options::ImageOptions,
-    _config: &Config,
+    config: &Config,
     cx: &TraceContext,
 ) -> VipsResult<Image> {
     let mut span = tracer("shrinkray").start_with_context("output", cx);
 
-    let format = options.format.unwrap_or(options::ImageFormat::Jpeg);
+    let format = options.output_format();
+    if options.quality.is_none() {
+        options.quality = config.default_quality.get(format);
+        // High density images hide compression artefacts, so can use a lower quality
+        if let Some(factor) = config.dpr_quality_factor(options.device_pixel_ratio.unwrap_or(1.0)) {
+            let quality = options.quality.unwrap_or_else(|| format.default_quality());
+            options.quality = Some((f64::from(quality) * factor).round().clamp(1.0, 100.0) as i32);
+        }
+    }
 
     span.set_attributes([KeyValue::new("shrinkray.image.format", format.to_string())]);
 
+    // Formats without an alpha channel would otherwise show transparency as black
+    let flattened;
+    let image = if !format.supports_alpha() && image.image_hasalpha() {
+        let background = options.background.clone().unwrap_or_default();
+        flattened = flatten(image, &background, cx)?;
+        &flattened
+    } else {
+        image
+    };
+
     let result = match format {
         options::ImageFormat::Jpeg => Ok(Image {
             bytes: ops::jpegsave_buffer_with_opts(image, &options.into())?,
             content_type: options::ImageFormat::Jpeg,
         }),
         options::ImageFormat::Webp => Ok(Image {
-            bytes: ops::webpsave_buffer_with_opts(image, &options.into())?,
+            bytes: ops::webpsave_buffer_with_opts(
+                image,
+                &ops::WebpsaveBufferOptions {
+                    page_height: image.get_page_height(),
+                    ..options.into()
+                },
+            )?,
             content_type: options::ImageFormat::Webp,
         }),
+        options::ImageFormat::Gif => Ok(Image {
+            bytes: ops::gifsave_buffer_with_opts(
+                image,
+                &ops::GifsaveBufferOptions {
+                    page_height: image.get_page_height(),
+                    profile: options.output_profile().to_string(),
+                    keep: options.keep_metadata(),
+                    ..Default::default()
+                },
+            )?,
+            content_type: options::ImageFormat::Gif,
+        }),
         options::ImageFormat::Avif => Ok(Image {
             bytes: ops::heifsave_buffer_with_opts(image, &options.into())?,
             content_type: options::ImageFormat::Avif,
@@ -291,11 +964,89 @@ fn output(
             bytes: ops::pngsave_buffer_with_opts(image, &options.into())?,
             content_type: options::ImageFormat::Png,
         }),
+        options::ImageFormat::Blurhash => Ok(Image {
+            bytes: blurhash(image, options, cx)?.into_bytes(),
+            content_type: options::ImageFormat::Blurhash,
+        }),
+        options::ImageFormat::Colour => {
+            let colour = average_colour(image, options, cx)?;
+            let json = serde_json::json!({
+                "r": colour.r,
+                "g": colour.g,
+                "b": colour.b,
+                "hex": String::from(&colour),
+            });
+            Ok(Image {
+                bytes: json.to_string().into_bytes(),
+                content_type: options::ImageFormat::Colour,
+            })
+        }
+        options::ImageFormat::Meta => unreachable!("metadata is returned before processing"),
     };
     <dyn ObjectSafeSpan>::end(&mut span);
     result
 }
 
+/// Downsample the image and encode it as a BlurHash placeholder
+fn blurhash(
+    image: &VipsImage,
+    options: &options::ImageOptions,
+    cx: &TraceContext,
+) -> VipsResult<String> {
+    // BlurHash only captures low frequencies, so a small image is enough
+    let opts = ops::ThumbnailImageOptions {
+        height: 32,
+        ..Default::default()
+    };
+    let mut small = ops::thumbnail_image_with_opts(image, 32, &opts)?;
+    if small.image_hasalpha() {
+        let background = options.background.clone().unwrap_or_default();
+        small = flatten(&small, &background, cx)?;
+    }
+    let small = ops::cast(&small, ops::BandFormat::Uchar)?;
+
+    Ok(crate::blurhash::encode(
+        &small.image_write_to_memory(),
+        usize::try_from(small.get_width()).unwrap_or(0),
+        usize::try_from(small.get_height()).unwrap_or(0),
+        usize::try_from(small.get_bands()).unwrap_or(3),
+        options.blurhash_x.unwrap_or(4),
+        options.blurhash_y.unwrap_or(3),
+    ))
+}
+
+/// Reduce the image to a single pixel to find its average colour
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn average_colour(
+    image: &VipsImage,
+    options: &options::ImageOptions,
+    cx: &TraceContext,
+) -> VipsResult<options::Colour> {
+    let mut image = image.clone();
+    if image.image_hasalpha() {
+        let background = options.background.clone().unwrap_or_default();
+        image = flatten(&image, &background, cx)?;
+    }
+    let opts = ops::ThumbnailImageOptions {
+        height: 1,
+        size: ops::Size::Force,
+        ..Default::default()
+    };
+    let pixel = ops::thumbnail_image_with_opts(&image, 1, &opts)?;
+    let point = ops::getpoint(&pixel, 0, 0)?;
+
+    // Greyscale images have a single band
+    let channel = |band: usize| {
+        let value = point.get(band).or(point.first()).copied().unwrap_or(0.0);
+        value.round().clamp(0.0, 255.0) as u8
+    };
+    Ok(options::Colour {
+        r: channel(0),
+        g: channel(1),
+        b: channel(2),
+    })
+}
+
 fn rotate(
     image: &VipsImage,
     options: &options::ImageOptions,
@@ -312,6 +1063,25 @@ fn rotate(
     Ok(image)
 }
 
+fn flip(
+    image: &VipsImage,
+    options: &options::ImageOptions,
+    cx: &TraceContext,
+) -> VipsResult<VipsImage> {
+    let mut span = tracer("shrinkray").start_with_context("flip", cx);
+
+    let result = match (options.flip == Some(true), options.flop == Some(true)) {
+        (true, true) => ops::flip(
+            &ops::flip(image, ops::Direction::Vertical)?,
+            ops::Direction::Horizontal,
+        ),
+        (true, false) => ops::flip(image, ops::Direction::Vertical),
+        _ => ops::flip(image, ops::Direction::Horizontal),
+    };
+    <dyn ObjectSafeSpan>::end(&mut span);
+    result
+}
+
 #[allow(clippy::cast_possible_truncation)]
 fn resize(
     image: &VipsImage,
@@ -330,15 +1100,57 @@ fn resize(
             options.height.map_or(0, i64::from),
         ),
     ]);
+    // Thumbnail always uses its own kernel. Animations stay on thumbnail so
+    // each frame is resized separately.
+    if let Some(kernel) = options.kernel
+        && image.get_page_height() == image.get_height()
+    {
+        let result =
+            resize_with_kernel(image, options, kernel.to_vips(), image_width, image_height);
+        <dyn ObjectSafeSpan>::end(&mut span);
+        return result;
+    }
+    if options.fit == Some(options::Fit::Fill)
+        && let (Some(width), Some(height)) = (options.width, options.height)
+    {
+        // Resize each axis independently to the exact dimensions
+        let thumbnail_options = ops::ThumbnailImageOptions {
+            height,
+            import_profile: "sRGB".to_string(),
+            export_profile: options.output_profile().to_string(),
+            size: ops::Size::Force,
+            ..Default::default()
+        };
+        let result = ops::thumbnail_image_with_opts(image, width, &thumbnail_options);
+        <dyn ObjectSafeSpan>::end(&mut span);
+        return result;
+    }
+    let fit_crop = options.fit == Some(options::Fit::Crop);
+    let gravity = options.gravity.unwrap_or_default();
+    let interesting = options.crop.and_then(options::Crop::interesting);
+    if fit_crop && interesting.is_none() && gravity != options::Gravity::Centre {
+        let result = resize_with_gravity(image, options, gravity, image_width, image_height);
+        <dyn ObjectSafeSpan>::end(&mut span);
+        return result;
+    }
     let scale = options.get_resize_scale(image_width, image_height);
     let mut thumbnail_options = ops::ThumbnailImageOptions {
         import_profile: "sRGB".to_string(),
-        export_profile: "sRGB".to_string(),
+        export_profile: options.output_profile().to_string(),
         crop: ops::Interesting::Centre,
         linear: false,
         size: ops::Size::Both,
         ..Default::default()
     };
+    // Content-aware cropping needs an image at least as large as the target,
+    // otherwise fall back to a centred crop
+    if fit_crop
+        && let Some(interesting) = interesting
+        && image_width >= options.width.unwrap_or(0)
+        && image_height >= options.height.unwrap_or(0)
+    {
+        thumbnail_options.crop = interesting;
+    }
     if options.height.is_some() {
         thumbnail_options.height = options.height.unwrap_or(0);
     } else {
@@ -350,6 +1162,411 @@ fn resize(
     result
 }
 
+/// Set the opacity of an image, adding an alpha channel if required
+fn set_opacity(image: &VipsImage, opacity: options::Percentage) -> VipsResult<VipsImage> {
+    let image = if image.image_hasalpha() {
+        ops::copy(image)?
+    } else {
+        ops::bandjoin_const(image, &mut [255.0])?
+    };
+    if opacity == options::Percentage(100) {
+        return Ok(image);
+    }
+    let bands = usize::try_from(image.get_bands()).unwrap_or(4);
+    let mut multiply = vec![1.0; bands];
+    let mut addition = vec![0.0; bands];
+    multiply[bands - 1] = f64::from(opacity.0) / 100.0;
+    let opts = ops::LinearOptions { uchar: true };
+    ops::linear_with_opts(&image, &mut multiply, &mut addition, &opts)
+}
+
+fn composite_watermark(
+    image: &VipsImage,
+    watermark: &[u8],
+    options: &options::ImageOptions,
+) -> VipsResult<VipsImage> {
+    let image_width = image.get_width();
+    let image_height = image.get_height();
+
+    let mut overlay = VipsImage::new_from_buffer(watermark, "")?;
+    overlay = ops::colourspace(&overlay, ops::Interpretation::Srgb)?;
+
+    // Scale relative to the image width, never exceeding the image bounds
+    let mut scale = options.watermark_scale.map_or(1.0, |percentage| {
+        f64::from(image_width) * f64::from(percentage.0) / 100.0 / f64::from(overlay.get_width())
+    });
+    scale = scale
+        .min(f64::from(image_width) / f64::from(overlay.get_width()))
+        .min(f64::from(image_height) / f64::from(overlay.get_height()));
+    if (scale - 1.0).abs() > f64::EPSILON {
+        overlay = with_premultiplied(&overlay, |overlay| ops::resize(overlay, scale))?;
+    }
+
+    let opacity = options
+        .watermark_opacity
+        .unwrap_or(options::Percentage(100));
+    overlay = set_opacity(&overlay, opacity)?;
+
+    let gravity = options
+        .watermark_gravity
+        .unwrap_or(options::Gravity::SouthEast);
+    let (x, y) = gravity.offsets(
+        (image_width, image_height),
+        (overlay.get_width(), overlay.get_height()),
+    );
+    let opts = ops::Composite2Options {
+        x,
+        y,
+        ..Default::default()
+    };
+    let composited = ops::composite_2_with_opts(image, &overlay, ops::BlendMode::Over, &opts)?;
+
+    // Don't introduce an alpha channel the source didn't have
+    if image.image_hasalpha() {
+        Ok(composited)
+    } else {
+        ops::flatten(&composited)
+    }
+}
+
+fn apply_watermark(
+    image: &VipsImage,
+    watermark: &[u8],
+    options: &options::ImageOptions,
+    cx: &TraceContext,
+) -> VipsResult<VipsImage> {
+    let mut span = tracer("shrinkray").start_with_context("watermark", cx);
+    let result = composite_watermark(image, watermark, options);
+    <dyn ObjectSafeSpan>::end(&mut span);
+    result
+}
+
+/// Composite a second image at an exact position, unlike a watermark it isn't scaled
+fn apply_overlay(
+    image: &VipsImage,
+    overlay: &[u8],
+    options: &options::ImageOptions,
+    cx: &TraceContext,
+) -> VipsResult<VipsImage> {
+    let mut span = tracer("shrinkray").start_with_context("overlay", cx);
+    let result = VipsImage::new_from_buffer(overlay, "").and_then(|overlay| {
+        let overlay = ops::colourspace(&overlay, ops::Interpretation::Srgb)?;
+        let opts = ops::Composite2Options {
+            x: options.overlay_x.unwrap_or(0),
+            y: options.overlay_y.unwrap_or(0),
+            ..Default::default()
+        };
+        let blend = options
+            .overlay_blend
+            .unwrap_or(options::BlendMode::Over)
+            .to_vips();
+        let composited = ops::composite_2_with_opts(image, &overlay, blend, &opts)?;
+
+        // Don't introduce an alpha channel the source didn't have
+        if image.image_hasalpha() {
+            Ok(composited)
+        } else {
+            ops::flatten(&composited)
+        }
+    });
+    <dyn ObjectSafeSpan>::end(&mut span);
+    result
+}
+
+/// Escape text so it is rendered literally rather than interpreted as Pango markup
+fn escape_markup(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn render_text(
+    image: &VipsImage,
+    text: &str,
+    options: &options::ImageOptions,
+) -> VipsResult<VipsImage> {
+    let image_width = image.get_width();
+    let image_height = image.get_height();
+
+    // Default to a size proportional to the image height
+    let size = options.text_size.unwrap_or((image_height / 20).max(12));
+    let padding = size / 2;
+    let area_width = (image_width - padding * 2).max(1);
+    let area_height = (image_height - padding * 2).max(1);
+
+    // At 72 DPI one point is one pixel
+    let opts = ops::TextOptions {
+        font: format!("sans {size}"),
+        width: area_width,
+        dpi: 72,
+        ..Default::default()
+    };
+    let mut mask = ops::text_with_opts(&escape_markup(text), &opts)?;
+    if mask.get_width() > area_width || mask.get_height() > area_height {
+        mask = ops::extract_area(
+            &mask,
+            0,
+            0,
+            mask.get_width().min(area_width),
+            mask.get_height().min(area_height),
+        )?;
+    }
+
+    let gravity = options.text_gravity.unwrap_or(options::Gravity::South);
+    let (x, y) = gravity.offsets(
+        (area_width, area_height),
+        (mask.get_width(), mask.get_height()),
+    );
+    let mask = ops::embed(&mask, x + padding, y + padding, image_width, image_height)?;
+
+    let mut pixel: Vec<f64> = options
+        .text_colour
+        .as_ref()
+        .map_or(vec![0.0, 0.0, 0.0], std::convert::Into::into);
+    if image.image_hasalpha() {
+        pixel.push(255.0);
+    }
+    let fill = VipsImage::new_from_image(image, &pixel)?;
+
+    let opts = ops::IfthenelseOptions { blend: true };
+    ops::ifthenelse_with_opts(&mask, &fill, image, &opts)
+}
+
+/// Centre the image within `dimensions`, filling the remaining area with the background
+fn pad(
+    image: &VipsImage,
+    dimensions: (i32, i32),
+    options: &options::ImageOptions,
+    cx: &TraceContext,
+) -> VipsResult<VipsImage> {
+    let mut span = tracer("shrinkray").start_with_context("pad", cx);
+    let (width, height) = dimensions;
+    let width = width.max(image.get_width());
+    let height = height.max(image.get_height());
+
+    // Without a background, pad with transparency when the output format allows it
+    let transparent = options.background.is_none() && options.output_format().supports_alpha();
+    let (image, background) = if transparent {
+        let image = if image.image_hasalpha() {
+            ops::copy(image)?
+        } else {
+            ops::bandjoin_const(image, &mut [255.0])?
+        };
+        let bands = usize::try_from(image.get_bands()).unwrap_or(4);
+        (image, vec![0.0; bands])
+    } else {
+        let mut background: Vec<f64> = options
+            .background
+            .as_ref()
+            .map_or(vec![255.0, 255.0, 255.0], std::convert::Into::into);
+        if image.image_hasalpha() {
+            background.push(255.0);
+        }
+        (ops::copy(image)?, background)
+    };
+
+    let gravity = options.gravity.unwrap_or_default();
+    let (x, y) = gravity.offsets((width, height), (image.get_width(), image.get_height()));
+    let opts = ops::EmbedOptions {
+        extend: ops::Extend::Background,
+        background,
+    };
+    let result = ops::embed_with_opts(&image, x, y, width, height, &opts);
+    <dyn ObjectSafeSpan>::end(&mut span);
+    result
+}
+
+/// Surround the image with a solid border, increasing its dimensions by `border` on each side
+fn add_border(
+    image: &VipsImage,
+    border: i32,
+    options: &options::ImageOptions,
+    cx: &TraceContext,
+) -> VipsResult<VipsImage> {
+    let mut span = tracer("shrinkray").start_with_context("border", cx);
+    let mut background: Vec<f64> = options
+        .border_colour
+        .as_ref()
+        .map_or(vec![0.0, 0.0, 0.0], std::convert::Into::into);
+    if image.image_hasalpha() {
+        background.push(255.0);
+    }
+    let opts = ops::EmbedOptions {
+        extend: ops::Extend::Background,
+        background,
+    };
+    let result = ops::embed_with_opts(
+        image,
+        border,
+        border,
+        image.get_width() + border * 2,
+        image.get_height() + border * 2,
+        &opts,
+    );
+    <dyn ObjectSafeSpan>::end(&mut span);
+    result
+}
+
+/// Composite a radial gradient from transparent to black over the image
+fn vignette(image: &VipsImage, strength: options::Percentage) -> VipsResult<VipsImage> {
+    let width = image.get_width();
+    let height = image.get_height();
+    let opacity = f64::from(strength.0.clamp(0, 100)) / 100.0;
+
+    // The gradient is relative to the bounding box, so it follows the aspect ratio
+    let svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}"><defs><radialGradient id="v" r="0.71"><stop offset="0.4" stop-opacity="0"/><stop offset="1" stop-opacity="{opacity}"/></radialGradient></defs><rect width="{width}" height="{height}" fill="url(#v)"/></svg>"#
+    );
+    let overlay = VipsImage::new_from_buffer(svg.as_bytes(), "")?;
+    let composited = ops::composite_2(image, &overlay, ops::BlendMode::Over)?;
+
+    // Don't introduce an alpha channel the source didn't have
+    if image.image_hasalpha() {
+        Ok(composited)
+    } else {
+        ops::flatten(&composited)
+    }
+}
+
+/// Mask the image with a rounded rectangle, making the corners transparent
+fn round_corners(image: &VipsImage, radius: options::Radius) -> VipsResult<VipsImage> {
+    let width = image.get_width();
+    let height = image.get_height();
+    let radius = radius.pixels(width, height);
+
+    let svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}"><rect x="0" y="0" width="{width}" height="{height}" rx="{radius}" ry="{radius}" fill="white"/></svg>"#
+    );
+    let rect = VipsImage::new_from_buffer(svg.as_bytes(), "")?;
+    let mask = ops::extract_band(&rect, 3)?;
+
+    if image.image_hasalpha() {
+        // Combine the mask with the existing alpha channel
+        let bands = image.get_bands();
+        let colour =
+            ops::extract_band_with_opts(image, 0, &ops::ExtractBandOptions { n: bands - 1 })?;
+        let alpha = ops::extract_band(image, bands - 1)?;
+        let alpha = ops::multiply(&alpha, &mask)?;
+        let alpha = ops::linear_with_opts(
+            &alpha,
+            &mut [1.0 / 255.0],
+            &mut [0.0],
+            &ops::LinearOptions { uchar: true },
+        )?;
+        ops::bandjoin(&mut [colour, alpha])
+    } else {
+        ops::bandjoin(&mut [ops::copy(image)?, mask])
+    }
+}
+
+/// Resize to cover the requested dimensions, then crop the excess anchored to `gravity`
+#[allow(clippy::cast_possible_truncation)]
+fn resize_with_gravity(
+    image: &VipsImage,
+    options: &options::ImageOptions,
+    gravity: options::Gravity,
+    image_width: i32,
+    image_height: i32,
+) -> VipsResult<VipsImage> {
+    let width = options.width.unwrap_or(image_width);
+    let height = options.height.unwrap_or(image_height);
+    let scale = (f64::from(width) / f64::from(image_width))
+        .max(f64::from(height) / f64::from(image_height));
+    let scaled_width = ((f64::from(image_width) * scale).round() as i32).max(width);
+    let scaled_height = ((f64::from(image_height) * scale).round() as i32).max(height);
+
+    let thumbnail_options = ops::ThumbnailImageOptions {
+        height: scaled_height,
+        import_profile: "sRGB".to_string(),
+        export_profile: options.output_profile().to_string(),
+        crop: ops::Interesting::None,
+        linear: false,
+        size: ops::Size::Force,
+        ..Default::default()
+    };
+    let resized = ops::thumbnail_image_with_opts(image, scaled_width, &thumbnail_options)?;
+
+    let (left, top) = gravity.offsets((scaled_width, scaled_height), (width, height));
+    ops::extract_area(&resized, left, top, width, height)
+}
+
+/// Resize with an explicit resampling kernel, matching the crops of the thumbnail path.
+fn resize_with_kernel(
+    image: &VipsImage,
+    options: &options::ImageOptions,
+    kernel: ops::Kernel,
+    image_width: i32,
+    image_height: i32,
+) -> VipsResult<VipsImage> {
+    let width = options.width.unwrap_or(image_width);
+    let height = options.height.unwrap_or(image_height);
+    let fit_crop = options.fit == Some(options::Fit::Crop);
+    let (scaled_width, scaled_height) = if options.fit == Some(options::Fit::Fill) {
+        (width, height)
+    } else {
+        let scale = (f64::from(width) / f64::from(image_width))
+            .max(f64::from(height) / f64::from(image_height));
+        (
+            ((f64::from(image_width) * scale).round() as i32).max(width),
+            ((f64::from(image_height) * scale).round() as i32).max(height),
+        )
+    };
+
+    // Convert to the output profile with thumbnail at the original size, so colours
+    // match the other paths, then resample with the requested kernel
+    let thumbnail_options = ops::ThumbnailImageOptions {
+        height: image_height,
+        import_profile: "sRGB".to_string(),
+        export_profile: options.output_profile().to_string(),
+        size: ops::Size::Force,
+        ..Default::default()
+    };
+    let image = ops::thumbnail_image_with_opts(image, image_width, &thumbnail_options)?;
+    let resize_options = ops::ResizeOptions {
+        kernel,
+        vscale: f64::from(scaled_height) / f64::from(image_height),
+        ..Default::default()
+    };
+    let resized = with_premultiplied(&image, |image| {
+        ops::resize_with_opts(
+            image,
+            f64::from(scaled_width) / f64::from(image_width),
+            &resize_options,
+        )
+    })?;
+    if (scaled_width, scaled_height) == (width, height) {
+        return Ok(resized);
+    }
+
+    if fit_crop
+        && let Some(interesting) = options.crop.and_then(options::Crop::interesting)
+        && image_width >= width
+        && image_height >= height
+    {
+        let smartcrop_options = ops::SmartcropOptions {
+            interesting,
+            ..Default::default()
+        };
+        return ops::smartcrop_with_opts(&resized, width, height, &smartcrop_options);
+    }
+    let gravity = if fit_crop {
+        options.gravity.unwrap_or_default()
+    } else {
+        options::Gravity::Centre
+    };
+    let (left, top) = gravity.offsets((scaled_width, scaled_height), (width, height));
+    ops::extract_area(&resized, left, top, width, height)
+}
+
 const KODACHROME: [f64; 9] = [
     1.12855, -0.39673, -0.03992, -0.16404, 1.08352, -0.05498, -0.16786, -0.56034, 1.60148,
 ];
@@ -375,45 +1592,543 @@ fn apply_style(
     opacity: Option<options::Percentage>,
 ) -> VipsResult<VipsImage> {
     let matrix = VipsImage::image_new_matrix_from_array(3, 3, &array)?;
-    let mut overlay = ops::recomb(image, &matrix)?;
-
-    // Convert to float band format to apply the opacity
-    overlay = ops::cast(&overlay, ops::BandFormat::Float)?;
 
-    overlay = if overlay.image_hasalpha() {
-        overlay
+    // Recombine the colour bands only, so any transparency is kept as it is
+    let (colour, alpha) = if image.image_hasalpha() {
+        let bands = image.get_bands();
+        let colour =
+            ops::extract_band_with_opts(image, 0, &ops::ExtractBandOptions { n: bands - 1 })?;
+        (colour, Some(ops::extract_band(image, bands - 1)?))
     } else {
-        ops::bandjoin_const(&overlay, &mut [255.0])?
+        (ops::copy(image)?, None)
     };
+    let mut styled = ops::recomb(&colour, &matrix)?;
 
     let opacity = opacity.unwrap_or(options::Percentage(100));
+    if opacity != options::Percentage(100) {
+        // Blend the styled colours with the originals by the opacity
+        let amount = f64::from(opacity.0) / 100.0;
+        let styled_part = ops::linear(&styled, &mut [amount], &mut [0.0])?;
+        let original_part = ops::linear(&colour, &mut [1.0 - amount], &mut [0.0])?;
+        styled = ops::add(&styled_part, &original_part)?;
+    }
+    let styled = ops::cast(&styled, colour.get_format()?)?;
 
-    let multiply = [1.0, 1.0, 1.0, f64::from(opacity.0) / 100.0];
-    let addition = [0.0, 0.0, 0.0, 0.0];
-    let mut multiply = multiply.to_vec();
-    let mut addition = addition.to_vec();
-    overlay = ops::linear(&overlay, &mut multiply, &mut addition)?;
+    match alpha {
+        Some(alpha) => ops::bandjoin(&mut [styled, alpha]),
+        None => Ok(styled),
+    }
+}
 
-    if opacity == options::Percentage(100) {
-        // Return the overlay image without blending
-        return ops::cast(&overlay, ops::BandFormat::Uchar);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::create_vips_app;
+    use rstest::rstest;
+
+    #[test]
+    fn test_animated_gif_preserved() {
+        create_vips_app();
+        let bytes = include_bytes!("../tests/fixtures/animated.gif");
+        let mut options = options::ImageOptions {
+            width: Some(4),
+            format: Some(options::ImageFormat::Gif),
+            ..Default::default()
+        };
+        let image = process_image(
+            bytes,
+            &Overlays::default(),
+            &mut options,
+            &Config::default(),
+            &TraceContext::new(),
+        )
+        .expect("failed to process image");
+
+        let output = VipsImage::new_from_buffer(&image.bytes, "[n=-1]").unwrap();
+        assert_eq!(output.get_n_pages(), 3);
+        assert_eq!(output.get_width(), 4);
+        assert_eq!(output.get_page_height(), 4);
     }
 
-    overlay = ops::composite_2(image, &overlay, ops::BlendMode::Over)?;
+    #[test]
+    fn test_animation_frame() {
+        create_vips_app();
+        let bytes = include_bytes!("../tests/fixtures/animated.gif");
+        let mut options = options::ImageOptions {
+            frame: Some(1),
+            format: Some(options::ImageFormat::Gif),
+            ..Default::default()
+        };
+        let image = process_image(
+            bytes,
+            &Overlays::default(),
+            &mut options,
+            &Config::default(),
+            &TraceContext::new(),
+        )
+        .expect("failed to process image");
 
-    overlay = ops::cast(&overlay, ops::BandFormat::Uchar)?;
+        let output = VipsImage::new_from_buffer(&image.bytes, "[n=-1]").unwrap();
+        assert_eq!(output.get_n_pages(), 1);
+        assert_eq!(output.get_page_height(), 8);
+    }
 
-    let colour = &options::Colour {
-        r: 255,
-        g: 255,
-        b: 255,
-    };
+    // The fixtures store 8x8 grey quadrants of 0, 85, 170 and 255 from the top
+    // left, so the top row after rotation identifies the orientation
+    #[rstest]
+    #[case::normal(include_bytes!("../tests/fixtures/orientation-1.jpg"), [0, 85])]
+    #[case::mirror_horizontal(include_bytes!("../tests/fixtures/orientation-2.jpg"), [85, 0])]
+    #[case::rotate_180(include_bytes!("../tests/fixtures/orientation-3.jpg"), [255, 170])]
+    #[case::mirror_vertical(include_bytes!("../tests/fixtures/orientation-4.jpg"), [170, 255])]
+    #[case::transpose(include_bytes!("../tests/fixtures/orientation-5.jpg"), [0, 170])]
+    #[case::rotate_90(include_bytes!("../tests/fixtures/orientation-6.jpg"), [170, 0])]
+    #[case::transverse(include_bytes!("../tests/fixtures/orientation-7.jpg"), [255, 85])]
+    #[case::rotate_270(include_bytes!("../tests/fixtures/orientation-8.jpg"), [85, 255])]
+    fn test_exif_orientation(#[case] bytes: &[u8], #[case] top: [i32; 2]) {
+        create_vips_app();
+        assert_eq!(needs_rotation(bytes), top != [0, 85]);
+        let mut options = options::ImageOptions {
+            format: Some(options::ImageFormat::Png),
+            ..Default::default()
+        };
+        let image = process_image(
+            bytes,
+            &Overlays::default(),
+            &mut options,
+            &Config::default(),
+            &TraceContext::new(),
+        )
+        .expect("failed to process image");
 
-    let opts = ops::FlattenOptions {
-        background: colour.into(),
+        let output = VipsImage::new_from_buffer(&image.bytes, "").unwrap();
+        let grey = |x| ops::getpoint(&output, x, 4).unwrap()[0].round() as i32;
+        assert_eq!([grey(4), grey(12)], top);
+    }
+
+    // The fixture's left half is opaque red and its right half is transparent
+    // green, which would show as a green fringe if it bled into the edge
+    #[rstest]
+    #[case::kernel_resize(options::ImageOptions {
+        width: Some(32),
+        kernel: Some(options::Kernel::Lanczos3),
         ..Default::default()
-    };
-    overlay = ops::flatten_with_opts(&overlay, &opts)?;
+    })]
+    #[case::blur(options::ImageOptions {
+        blur: Some(Percentage(10)),
+        ..Default::default()
+    })]
+    fn test_transparent_edges(#[case] mut options: options::ImageOptions) {
+        create_vips_app();
+        options.format = Some(options::ImageFormat::Png);
+        let image = process_image(
+            include_bytes!("../tests/fixtures/transparent-edge.png"),
+            &Overlays::default(),
+            &mut options,
+            &Config::default(),
+            &TraceContext::new(),
+        )
+        .expect("failed to process image");
+
+        let output = VipsImage::new_from_buffer(&image.bytes, "").unwrap();
+        assert!(output.image_hasalpha());
+        let mut partial = 0;
+        for x in 0..output.get_width() {
+            let pixel = ops::getpoint(&output, x, 4).unwrap();
+            if pixel[3] > 0.0 {
+                assert!(pixel[1] < 1.0, "green fringe at x={x}: {pixel:?}");
+            }
+            if pixel[3] > 0.0 && pixel[3] < 255.0 {
+                partial += 1;
+            }
+        }
+        assert!(partial > 0, "expected a partially transparent edge");
+    }
+
+    #[rstest]
+    #[case::white(None, [255, 255, 255])]
+    #[case::background(Some(options::Colour { r: 0, g: 0, b: 255 }), [0, 0, 255])]
+    fn test_jpeg_flattens_alpha(
+        #[case] background: Option<options::Colour>,
+        #[case] expected: [i32; 3],
+    ) {
+        create_vips_app();
+        let mut options = options::ImageOptions {
+            width: Some(16),
+            format: Some(options::ImageFormat::Jpeg),
+            background,
+            ..Default::default()
+        };
+        let image = process_image(
+            include_bytes!("../tests/fixtures/transparent-edge.png"),
+            &Overlays::default(),
+            &mut options,
+            &Config::default(),
+            &TraceContext::new(),
+        )
+        .expect("failed to process image");
 
-    Ok(overlay)
+        let output = VipsImage::new_from_buffer(&image.bytes, "").unwrap();
+        assert!(!output.image_hasalpha());
+        let pixel = ops::getpoint(&output, 14, 8).unwrap();
+        let colour: Vec<i32> = pixel.iter().map(|value| value.round() as i32).collect();
+        // Allow for JPEG compression
+        for (value, expected) in colour.iter().zip(expected) {
+            assert!((value - expected).abs() <= 8, "{colour:?} != {expected:?}");
+        }
+    }
+
+    // Sepia of the fixture's opaque red is (100, 89, 69)
+    #[rstest]
+    #[case::full(Percentage(100), [100, 89, 69])]
+    #[case::half(Percentage(50), [178, 44, 35])]
+    fn test_style_keeps_alpha(#[case] sepia: Percentage, #[case] expected: [i32; 3]) {
+        create_vips_app();
+        let mut options = options::ImageOptions {
+            sepia: Some(sepia),
+            format: Some(options::ImageFormat::Png),
+            ..Default::default()
+        };
+        let image = process_image(
+            include_bytes!("../tests/fixtures/transparent-edge.png"),
+            &Overlays::default(),
+            &mut options,
+            &Config::default(),
+            &TraceContext::new(),
+        )
+        .expect("failed to process image");
+
+        let output = VipsImage::new_from_buffer(&image.bytes, "").unwrap();
+        let pixel = |x| -> Vec<i32> {
+            ops::getpoint(&output, x, 8)
+                .unwrap()
+                .iter()
+                .map(|value| value.round() as i32)
+                .collect()
+        };
+        let opaque = pixel(2);
+        assert_eq!(opaque[3], 255);
+        for (value, expected) in opaque.iter().zip(expected) {
+            assert!((value - expected).abs() <= 2, "{opaque:?} != {expected:?}");
+        }
+        assert_eq!(pixel(13)[3], 0);
+    }
+
+    #[test]
+    fn test_pixelate() {
+        create_vips_app();
+        // A horizontal and vertical gradient, so every source pixel differs
+        let xyz = ops::xyz(24, 16).unwrap();
+        let image = ops::cast(
+            &ops::linear(&xyz, &mut [10.0, 5.0], &mut [0.0, 0.0]).unwrap(),
+            ops::BandFormat::Uchar,
+        )
+        .unwrap();
+        let output = pixelate(&image, 8, &TraceContext::new()).unwrap();
+        assert_eq!((output.get_width(), output.get_height()), (24, 16));
+
+        let pixel = |x, y| ops::getpoint(&output, x, y).unwrap();
+        for (x, y) in [(0, 0), (8, 0), (16, 8)] {
+            let corner = pixel(x, y);
+            // Every pixel in the block matches its corner
+            for (dx, dy) in [(1, 0), (7, 0), (0, 7), (7, 7), (3, 4)] {
+                assert_eq!(pixel(x + dx, y + dy), corner, "block at ({x}, {y})");
+            }
+        }
+        assert_ne!(pixel(0, 0), pixel(8, 0));
+        assert_ne!(pixel(0, 0), pixel(0, 8));
+    }
+
+    /// Resize stripes of alternating black and white columns with `kernel`
+    fn resize_stripes(kernel: options::Kernel) -> Vec<i32> {
+        let stripes = pixel_row(&[0.0, 255.0].repeat(8), ops::BandFormat::Uchar);
+        let bytes = ops::replicate(&stripes, 1, 16)
+            .unwrap()
+            .image_write_to_buffer(".png")
+            .unwrap();
+        let mut options = options::ImageOptions {
+            width: Some(6),
+            height: Some(6),
+            kernel: Some(kernel),
+            format: Some(options::ImageFormat::Png),
+            ..Default::default()
+        };
+        let image = process_image(
+            &bytes,
+            &Overlays::default(),
+            &mut options,
+            &Config::default(),
+            &TraceContext::new(),
+        )
+        .expect("failed to process image");
+
+        let output = VipsImage::new_from_buffer(&image.bytes, "").unwrap();
+        assert_eq!((output.get_width(), output.get_height()), (6, 6));
+        row_values(&output)
+    }
+
+    #[test]
+    fn test_kernel_resize() {
+        create_vips_app();
+        // Nearest keeps whole stripes, while lanczos3 blends neighbouring columns
+        let nearest = resize_stripes(options::Kernel::Nearest);
+        assert!(
+            nearest.iter().all(|value| [0, 255].contains(value)),
+            "{nearest:?}"
+        );
+        assert_ne!(nearest, resize_stripes(options::Kernel::Lanczos3));
+    }
+
+    /// A single row of greyscale pixels in the given band format
+    fn pixel_row(values: &[f64], format: ops::BandFormat) -> VipsImage {
+        let width = i32::try_from(values.len()).unwrap();
+        let matrix = VipsImage::image_new_matrix_from_array(width, 1, values).unwrap();
+        ops::cast(&matrix, format).unwrap()
+    }
+
+    fn row_values(image: &VipsImage) -> Vec<i32> {
+        (0..image.get_width())
+            .map(|x| ops::getpoint(image, x, 0).unwrap()[0].round() as i32)
+            .collect()
+    }
+
+    #[rstest]
+    #[case::srgb("sRGB", false)]
+    #[case::p3("p3", true)]
+    fn test_wider_than_srgb(#[case] profile: &str, #[case] expected: bool) {
+        create_vips_app();
+        let source =
+            VipsImage::new_from_buffer(include_bytes!("../tests/fixtures/orientation-1.jpg"), "")
+                .unwrap();
+        let opts = ops::IccTransformOptions {
+            input_profile: "sRGB".to_string(),
+            ..Default::default()
+        };
+        let image = ops::icc_transform_with_opts(&source, profile, &opts).unwrap();
+        let bytes = ops::pngsave_buffer_with_opts(
+            &image,
+            &ops::PngsaveBufferOptions {
+                profile: profile.to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(wider_than_srgb(&bytes).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::uchar(ops::BandFormat::Uchar, &[0.0, 100.0, 255.0], &[255, 155, 0])]
+    #[case::ushort(ops::BandFormat::Ushort, &[0.0, 1000.0, 65535.0], &[65535, 64535, 0])]
+    fn test_negate(
+        #[case] format: ops::BandFormat,
+        #[case] values: &[f64],
+        #[case] expected: &[i32],
+    ) {
+        create_vips_app();
+        let image = pixel_row(values, format);
+        let output = linear_colour_bands(&image, -1.0, white_point(&image).unwrap()).unwrap();
+        assert_eq!(
+            discriminant(&output.get_format().unwrap()),
+            discriminant(&format)
+        );
+        assert_eq!(row_values(&output), expected);
+    }
+
+    #[rstest]
+    #[case::uchar(ops::BandFormat::Uchar, &[100.0, 200.0], &[72, 255])]
+    #[case::ushort(ops::BandFormat::Ushort, &[30000.0, 60000.0], &[27232, 65535])]
+    fn test_tone_contrast(
+        #[case] format: ops::BandFormat,
+        #[case] values: &[f64],
+        #[case] expected: &[i32],
+    ) {
+        create_vips_app();
+        let options = options::ImageOptions {
+            contrast: Some(Percentage(100)),
+            ..Default::default()
+        };
+        let output = tone(&pixel_row(values, format), &options, &TraceContext::new()).unwrap();
+        assert_eq!(
+            discriminant(&output.get_format().unwrap()),
+            discriminant(&format)
+        );
+        assert_eq!(row_values(&output), expected);
+    }
+
+    #[rstest]
+    #[case::uchar(ops::BandFormat::Uchar, [100.0, 200.0, 50.0], [200, 50, 100])]
+    #[case::ushort(ops::BandFormat::Ushort, [1000.0, 30000.0, 60000.0], [30000, 60000, 1000])]
+    fn test_apply_style(
+        #[case] format: ops::BandFormat,
+        #[case] colour: [f64; 3],
+        #[case] expected: [i32; 3],
+    ) {
+        create_vips_app();
+        let pixel = ops::bandjoin(&mut colour.map(|value| pixel_row(&[value], format))).unwrap();
+        // Rotate the channels so each output comes from a single input band
+        let rotate = [0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0];
+        let output = apply_style(&pixel, rotate, None).unwrap();
+        assert_eq!(
+            discriminant(&output.get_format().unwrap()),
+            discriminant(&format)
+        );
+        let values: Vec<i32> = ops::getpoint(&output, 0, 0)
+            .unwrap()
+            .iter()
+            .map(|value| value.round() as i32)
+            .collect();
+        assert_eq!(values, expected);
+    }
+
+    #[rstest]
+    #[case::uchar(ops::BandFormat::Uchar, &[0.0, 100.0, 200.0], &[0, 0, 255])]
+    #[case::ushort(ops::BandFormat::Ushort, &[0.0, 30000.0, 50000.0], &[0, 0, 65535])]
+    fn test_posterize(
+        #[case] format: ops::BandFormat,
+        #[case] values: &[f64],
+        #[case] expected: &[i32],
+    ) {
+        create_vips_app();
+        let output = posterize(&pixel_row(values, format), 2).unwrap();
+        assert_eq!(
+            discriminant(&output.get_format().unwrap()),
+            discriminant(&format)
+        );
+        assert_eq!(row_values(&output), expected);
+    }
+
+    #[test]
+    fn test_autolevel() {
+        create_vips_app();
+        let matrix = VipsImage::image_new_matrix_from_array(3, 1, &[100.0, 130.0, 150.0]).unwrap();
+        let image = ops::cast(&matrix, ops::BandFormat::Uchar).unwrap();
+        let output = autolevel(&image, &TraceContext::new()).unwrap();
+        let value = |x| ops::getpoint(&output, x, 0).unwrap()[0].round() as i32;
+        assert_eq!([value(0), value(1), value(2)], [0, 153, 255]);
+    }
+
+    #[test]
+    fn test_metadata() {
+        create_vips_app();
+        let bytes = include_bytes!("../tests/fixtures/animated.gif");
+        let mut options = options::ImageOptions {
+            meta: Some(true),
+            ..Default::default()
+        };
+        let image = process_image(
+            bytes,
+            &Overlays::default(),
+            &mut options,
+            &Config::default(),
+            &TraceContext::new(),
+        )
+        .expect("failed to read metadata");
+
+        assert_eq!(image.content_type, options::ImageFormat::Meta);
+        let meta: serde_json::Value = serde_json::from_slice(&image.bytes).unwrap();
+        assert_eq!(meta["width"], 8);
+        assert_eq!(meta["height"], 8);
+        assert_eq!(meta["pages"], 3);
+        assert_eq!(meta["format"], "gif");
+    }
+
+    #[test]
+    fn test_health_check() {
+        create_vips_app();
+        health_check(&Config::default(), &TraceContext::new()).expect("health check failed");
+    }
+
+    #[rstest]
+    #[case::jpeg(b"\xff\xd8\xff\xe0\x00\x10JFIF", Some(options::ImageFormat::Jpeg))]
+    #[case::png(b"\x89PNG\r\n\x1a\n", Some(options::ImageFormat::Png))]
+    #[case::gif87(b"GIF87a", Some(options::ImageFormat::Gif))]
+    #[case::gif89(b"GIF89a", Some(options::ImageFormat::Gif))]
+    #[case::webp(b"RIFF\x24\x00\x00\x00WEBPVP8 ", Some(options::ImageFormat::Webp))]
+    #[case::avif(b"\x00\x00\x00\x20ftypavif", Some(options::ImageFormat::Avif))]
+    #[case::heic(b"\x00\x00\x00\x18ftypheic", None)]
+    #[case::wave(b"RIFF\x24\x00\x00\x00WAVEfmt ", None)]
+    #[case::html(b"<!doctype html>", None)]
+    #[case::truncated(b"\xff\xd8", None)]
+    #[case::empty(b"", None)]
+    fn test_detect_format(#[case] bytes: &[u8], #[case] expected: Option<options::ImageFormat>) {
+        assert_eq!(detect_format(bytes), expected);
+    }
+
+    #[test]
+    fn test_source_header() {
+        create_vips_app();
+        let header = SourceHeader::read(include_bytes!("../tests/fixtures/animated.gif")).unwrap();
+        assert!(header.pages > 1);
+        assert_eq!(header.loader, Loader::Raster);
+
+        let tiff = ops::black(4, 4)
+            .unwrap()
+            .image_write_to_buffer(".tif")
+            .unwrap();
+        let header = SourceHeader::read(&tiff).unwrap();
+        assert_eq!((header.width, header.page_height), (4, 4));
+        let svg = b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"4\" height=\"4\"/>";
+        assert_eq!(SourceHeader::read(svg).unwrap().loader, Loader::Svg);
+
+        assert!(SourceHeader::read(b"<!doctype html><html></html>").is_err());
+        assert!(SourceHeader::read(b"").is_err());
+    }
+
+    #[rstest]
+    #[case::raster(Loader::Raster, 1, "", 5_000)]
+    #[case::pdf_default_dpi(Loader::Pdf, 1, "", 5_000)]
+    #[case::pdf_dpi(Loader::Pdf, 1, "dpi=144", 20_000)]
+    #[case::svg_density(Loader::Svg, 1, "density=720", 500_000)]
+    #[case::pdf_ignores_density(Loader::Pdf, 1, "density=720", 5_000)]
+    #[case::multi_page_pdf(Loader::Pdf, 20, "fm=gif", 5_000)]
+    #[case::multi_page_still(Loader::Raster, 20, "fm=png", 5_000)]
+    #[case::single_frame(Loader::Raster, 20, "fm=gif&frame=2", 5_000)]
+    #[case::animation(Loader::Raster, 20, "fm=gif", 100_000)]
+    fn test_decoded_pixels(
+        #[case] loader: Loader,
+        #[case] pages: i32,
+        #[case] query: &str,
+        #[case] expected: u64,
+    ) {
+        let header = SourceHeader {
+            width: 100,
+            page_height: 50,
+            pages,
+            loader,
+        };
+        let uri: axum::http::Uri = format!("/?{query}").parse().unwrap();
+        let options = axum::extract::Query::<options::ImageOptions>::try_from_uri(&uri)
+            .unwrap()
+            .0;
+        assert_eq!(header.decoded_pixels(&options), expected);
+    }
+
+    #[test]
+    fn test_source_content_type() {
+        assert_eq!(
+            source_content_type(include_bytes!("../tests/fixtures/animated.gif")),
+            "image/gif"
+        );
+        assert_eq!(source_content_type(b"\x89PNG\r\n\x1a\n"), "image/png");
+        assert_eq!(source_content_type(b"<svg/>"), "image/svg+xml");
+        assert_eq!(
+            source_content_type(b"<html></html>"),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn test_is_svg() {
+        assert!(is_svg(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"));
+        assert!(is_svg(b"\n<?xml version=\"1.0\"?>\n<!-- logo -->\n<svg/>"));
+        assert!(!is_svg(b"<html><body></body></html>"));
+        assert!(!is_svg(b"\x89PNG\r\n\x1a\n<svg"));
+    }
+
+    #[test]
+    fn test_escape_markup() {
+        assert_eq!(
+            escape_markup("<b>Tom & \"Jerry's\"</b>"),
+            "&lt;b&gt;Tom &amp; &quot;Jerry&apos;s&quot;&lt;/b&gt;"
+        );
+    }
 }
@@ -1,4 +1,5 @@
 use crate::http::HeaderMapExt;
+use crate::request_id::X_REQUEST_ID;
 use crate::service::Service;
 use axum::extract::State;
 use axum::{extract::Request, middleware::Next, response::IntoResponse};
@@ -27,10 +28,15 @@ pub async fn middleware(
     let method = req.method().to_string();
     let headers = req.headers();
     let remote_addr = headers
-        .get_x_forwarded_for(&ctx.config.proxies)
+        .get_x_forwarded_for(&ctx.config().proxies)
         .unwrap_or_default();
     let http_user_agent = headers.get_user_agent().unwrap_or_default();
     let http_referrer = headers.get_referrer().unwrap_or_default();
+    let request_id = headers
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
 
     let response = next.run(req).await;
 
@@ -42,7 +48,8 @@ pub async fn middleware(
         status = %response.status().as_u16(),
         response_time = start.elapsed().as_secs_f64(),
         %http_user_agent,
-        %http_referrer
+        %http_referrer,
+        %request_id
     );
 
     response
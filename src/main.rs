@@ -1,5 +1,10 @@
 mod backend;
+mod blurhash;
+mod cache;
+mod circuit;
+mod cli;
 mod config;
+mod cors;
 mod error;
 mod http;
 mod image;
@@ -7,25 +12,36 @@ mod logging;
 mod metrics;
 mod options;
 mod otel;
+mod path_options;
+mod ratelimit;
+mod request_id;
 mod service;
+mod tls;
 
 use axum::{
     Router,
-    extract::{Path, Query, State},
-    http::{HeaderMap, HeaderValue, StatusCode, header},
+    extract::{Path, Query, Request, State, rejection::QueryRejection},
+    http::{HeaderMap, HeaderValue, StatusCode, Uri, header},
     middleware,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     routing::get,
+    serve::ListenerExt,
 };
 use opentelemetry::trace::{Span, Status, TraceContextExt, Tracer};
 use opentelemetry::{Context as TraceContext, InstrumentationScope, KeyValue, global};
 use std::future::ready;
-use std::sync::Arc;
-use tracing::debug;
+use std::net::SocketAddr;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+use tower::{ServiceExt, service_fn};
+use tracing::{debug, error, info, warn};
 
-use backend::get_file_from_backend;
+use backend::{format_http_date, parse_http_date};
+use cache::ResponseCache;
+use chrono::{DateTime, Utc};
 use config::read_config;
 use error::Result;
+use http::HeaderMapExt;
 use service::Service;
 
 pub struct Routing {
@@ -37,16 +53,36 @@ pub struct Route {
     pub endpoint: String,
 }
 
-fn get_headers(image: &image::Image, download: Option<String>) -> Result<HeaderMap> {
+const DEFAULT_CACHE_CONTROL: &str = "public, max-age=31536000";
+
+fn get_headers(
+    content_type: &str,
+    content_length: usize,
+    download: Option<String>,
+    last_modified: Option<DateTime<Utc>>,
+    etag: Option<&str>,
+    vary: &[&'static str],
+    cache_control: &str,
+) -> Result<HeaderMap> {
     let mut headers = HeaderMap::new();
-    headers.insert(
-        header::CONTENT_TYPE,
-        HeaderValue::from_str(image.content_type.content_type())?,
-    );
-    headers.insert(
-        header::CACHE_CONTROL,
-        HeaderValue::from_static("public, max-age=31536000"),
-    );
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(content_type)?);
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_str(cache_control)?);
+    if let Some(last_modified) = last_modified {
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&format_http_date(&last_modified))?,
+        );
+    }
+    if let Some(etag) = etag {
+        headers.insert(header::ETAG, HeaderValue::from_str(etag)?);
+    }
+    // Ask browsers to send the client hints used to pick a device pixel ratio
+    headers.insert("accept-ch", HeaderValue::from_static("DPR"));
+    if !vary.is_empty() {
+        headers.insert(header::VARY, HeaderValue::from_str(&vary.join(", "))?);
+    }
     if let Some(filename) = download {
         headers.insert(
             header::CONTENT_DISPOSITION,
@@ -57,53 +93,375 @@ fn get_headers(image: &image::Image, download: Option<String>) -> Result<HeaderM
     Ok(headers)
 }
 
+/// Build the response for an image, serving only the requested bytes when the
+/// client sends a `Range` header.
+fn get_response(
+    request_headers: &HeaderMap,
+    mut headers: HeaderMap,
+    bytes: Vec<u8>,
+) -> Result<Response> {
+    let len = bytes.len();
+    match request_headers.get_byte_range(len) {
+        None => Ok((headers, bytes).into_response()),
+        Some(Ok(range)) => {
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", range.start, range.end - 1, len))?,
+            );
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(range.len()));
+            Ok((StatusCode::PARTIAL_CONTENT, headers, bytes[range].to_vec()).into_response())
+        }
+        Some(Err(_)) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", len))?,
+            );
+            Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response())
+        }
+    }
+}
+
+/// Reduce the output size for clients that send `Save-Data: on`, lowering the
+/// quality and switching to WebP when no format was requested and it's accepted.
+fn apply_save_data(
+    options: &mut options::ImageOptions,
+    request_headers: &HeaderMap,
+    vary: &mut Vec<&'static str>,
+    config: &config::Config,
+) {
+    let quality = config.save_data_quality;
+    options.quality = Some(options.quality.map_or(quality, |q| q.min(quality)));
+    if options.format.is_none() && options.meta.is_none() {
+        vary.push("Accept");
+        if request_headers.accepts("image/webp") {
+            options.format = Some(options::ImageFormat::Webp);
+        }
+    }
+}
+
+/// Reject requests for outputs larger than the configured limits. This is
+/// checked before any work is done, and again once the source's dimensions are
+/// known so a side derived from them is also limited.
+fn check_dimensions(
+    options: &options::ImageOptions,
+    source: Option<(i32, i32)>,
+    config: &config::Config,
+) -> Result<()> {
+    let (width, height) = match source {
+        Some(source) => options.output_dimensions(source),
+        None => options.requested_dimensions(),
+    };
+    if let (Some(max), Some(width)) = (config.max_width, width)
+        && width > i64::from(max)
+    {
+        return Err(error::Error::BadRequest(format!(
+            "width {width} exceeds the maximum of {max}"
+        )));
+    }
+    if let (Some(max), Some(height)) = (config.max_height, height)
+        && height > i64::from(max)
+    {
+        return Err(error::Error::BadRequest(format!(
+            "height {height} exceeds the maximum of {max}"
+        )));
+    }
+    if let (Some(max), Some(width), Some(height)) = (config.max_pixels, width, height)
+        && width.saturating_mul(height).unsigned_abs() > max
+    {
+        return Err(error::Error::BadRequest(format!(
+            "{width}x{height} exceeds the maximum of {max} pixels"
+        )));
+    }
+    Ok(())
+}
+
+/// Reject sources that are over the configured limits once decoded, or don't
+/// have the requested page or frame.
+fn check_source(
+    header: &image::SourceHeader,
+    options: &options::ImageOptions,
+    config: &config::Config,
+) -> Result<()> {
+    if let Some(max) = config.max_source_pixels {
+        let pixels = header.decoded_pixels(options);
+        if pixels > max {
+            return Err(error::Error::SourceTooLarge(format!(
+                "source of {pixels} pixels exceeds the maximum of {max} pixels"
+            )));
+        }
+    }
+    check_dimensions(options, Some((header.width, header.page_height)), config)?;
+
+    let pdf = header.loader == image::Loader::Pdf;
+    if let Some(page) = options.page
+        && pdf
+        && !(0..header.pages).contains(&page)
+    {
+        return Err(error::Error::NotFound);
+    }
+    if let Some(frame) = options.frame
+        && !pdf
+        && !(0..header.pages).contains(&frame)
+    {
+        return Err(error::Error::NotFound);
+    }
+    Ok(())
+}
+
+/// Whether the client's `If-Modified-Since` is no older than the upstream
+/// `Last-Modified`, so a 304 can be returned without processing.
+fn not_modified(request_headers: &HeaderMap, last_modified: Option<DateTime<Utc>>) -> bool {
+    let since = request_headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_http_date);
+    matches!((since, last_modified), (Some(since), Some(last_modified)) if last_modified <= since)
+}
+
+/// The `304 Not Modified` answer to a revalidation that matched `Last-Modified`
+fn not_modified_response(
+    cache_control: &str,
+    last_modified: Option<DateTime<Utc>>,
+) -> Result<Response> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_str(cache_control)?);
+    if let Some(last_modified) = last_modified {
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&format_http_date(&last_modified))?,
+        );
+    }
+    Ok((StatusCode::NOT_MODIFIED, headers).into_response())
+}
+
 async fn handle_image_request(
     State(ctx): State<Arc<Service>>,
     request_path: String,
+    uri: Uri,
     mut options: Query<options::ImageOptions>,
-    _headers: HeaderMap,
-    endpoint: String,
-    route_path: String,
+    request_headers: HeaderMap,
+    route: config::ConfigRouting,
     cx: TraceContext,
-) -> Result<impl IntoResponse> {
-    let relative_path = request_path.replacen(&route_path, "", 1);
-    let target = format!("{}{}", endpoint, relative_path);
+) -> Result<Response> {
+    let config = ctx.config();
+    let cache_control = route
+        .cache_control
+        .as_deref()
+        .unwrap_or(DEFAULT_CACHE_CONTROL);
+    let relative_path = request_path.replacen(&route.path, "", 1);
+    let target = format!("{}{}", route.endpoint, relative_path);
+
+    // Request headers that the response depends on
+    let mut vary = Vec::new();
+    let cache_key = if options.any_set() {
+        // A route's own secret takes precedence over the global one
+        if let Some(signing_secret) = route
+            .signing_secret
+            .as_ref()
+            .or(config.signing_secret.as_ref())
+            && !options.verify_signature(signing_secret, uri.path(), config.signing_algorithm)
+        {
+            return Err(error::Error::InvalidSignature);
+        }
+        // Presets are expanded after verification, so signatures cover the preset name
+        if let Some(name) = &options.preset {
+            let preset = config
+                .presets
+                .get(name)
+                .ok_or_else(|| error::Error::BadRequest(format!("unknown preset `{name}`")))?;
+            options.0 = options::with_preset(uri.query().unwrap_or_default(), &preset.0)
+                .map_err(error::Error::BadRequest)?;
+        }
+        // Fall back to the DPR client hint. It isn't covered by the signature,
+        // but is clamped like an explicit one.
+        if options.device_pixel_ratio.is_none() {
+            vary.push("DPR");
+            options.device_pixel_ratio = request_headers.get_dpr();
+        }
+        vary.push("Save-Data");
+        if request_headers.get_save_data() {
+            apply_save_data(&mut options, &request_headers, &mut vary, &config);
+        }
+        options.clamp_device_pixel_ratio(config.max_dpr);
+        check_dimensions(&options, None, &config)?;
+
+        (!ctx.cache.is_empty()).then(|| cache::cache_key(&target, &options))
+    } else {
+        None
+    };
+
+    // The weak ETag only depends on the request, so a matching revalidation is
+    // answered before the cache or backend are consulted
+    let etag = route
+        .weak_etag
+        .then(|| format!("W/\"{}\"", cache::cache_key(&target, &options)));
+    if let Some(etag) = &etag
+        && request_headers.if_none_match(etag)
+    {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, HeaderValue::from_str(etag)?);
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_str(cache_control)?);
+        if !vary.is_empty() {
+            headers.insert(header::VARY, HeaderValue::from_str(&vary.join(", "))?);
+        }
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    if let Some(key) = &cache_key {
+        let cached = ctx.cache.get(key).await;
+        metrics::record_cache_lookup(cached.is_some());
+        if let Some(cache::Entry {
+            image,
+            last_modified,
+        }) = cached
+        {
+            if not_modified(&request_headers, last_modified) {
+                return not_modified_response(cache_control, last_modified);
+            }
+            debug!("serving image from cache: {}", target);
+            metrics::record_output_bytes(Some(image.content_type), image.bytes.len());
+            return get_response(
+                &request_headers,
+                get_headers(
+                    image.content_type.content_type(),
+                    image.bytes.len(),
+                    options.download.clone(),
+                    last_modified,
+                    etag.as_deref(),
+                    &vary,
+                    cache_control,
+                )?,
+                image.bytes,
+            );
+        }
+    }
 
     debug!("fetching image from backend: {}", target);
     let mut span = global::tracer("shrinkray").start_with_context("get_file_from_backend", &cx);
-    let image = get_file_from_backend(&target, &ctx.config)
+    let backend_headers = route
+        .backend_headers
+        .to_header_map()
+        .map_err(|_| error::Error::InvalidBackend)?;
+    let result = ctx
+        .fetch(&target, &config, backend_headers)
         .await
         .inspect_err(|err| {
             span.set_status(Status::Error {
                 description: err.to_string().into(),
             });
-        })?;
+        });
     span.end();
 
+    // Serve the route's placeholder for missing images, with the same options applied
+    let (file, fallback_status) = match (result, &route.fallback_image) {
+        (Err(error::Error::NotFound), Some(fallback)) => {
+            debug!("serving fallback image: {}", fallback);
+            let file = ctx.fetch(fallback, &config, HeaderMap::new()).await?;
+            (file, Some(route.fallback_status()))
+        }
+        (result, _) => (result?, None),
+    };
+    // The placeholder shouldn't be cached in place of an image that may appear later
+    let cache_key = cache_key.filter(|_| fallback_status.is_none());
+    let etag = etag.filter(|_| fallback_status.is_none());
+    let cache_control = if fallback_status.is_some() {
+        "no-cache"
+    } else {
+        cache_control
+    };
+
+    let last_modified = file.last_modified;
+    if not_modified(&request_headers, last_modified) {
+        return not_modified_response(cache_control, last_modified);
+    }
+    let image = file.bytes;
+    metrics::record_source_bytes(image.len());
+
     if !options.any_set() {
         // If no options are set, return the original image
-        let image = image::Image {
-            bytes: image,
-            content_type: options::ImageFormat::Jpeg,
-        };
-        return Ok((get_headers(&image, options.download.clone())?, image.bytes));
+        metrics::record_output_bytes(image::detect_format(&image), image.len());
+        // The original is served unchanged, so describe it by its own format
+        let content_type = image::source_content_type(&image);
+        let mut headers = get_headers(
+            content_type,
+            image.len(),
+            options.download.clone(),
+            last_modified,
+            etag.as_deref(),
+            &[],
+            cache_control,
+        )?;
+        // Scripts in an SVG from the backend mustn't run on this origin
+        if content_type == "image/svg+xml" {
+            headers.insert(
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static("default-src 'none'; style-src 'unsafe-inline'; sandbox"),
+            );
+            headers.insert(
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            );
+        }
+        return get_response(&request_headers, headers, image)
+            .map(|response| with_status(response, fallback_status));
     }
 
     let download = options.download.clone();
 
-    if let Some(signing_secret) = &ctx.config.signing_secret
-        && !options.verify_signature(signing_secret)
-    {
-        return Err(error::Error::InvalidSignature);
+    let mut overlays = image::Overlays::default();
+    if let Some(watermark) = &options.watermark {
+        check_overlay_url(watermark, &route, &config)?;
+        debug!("fetching watermark from backend: {}", watermark);
+        overlays.watermark =
+            Some(fetch_overlay(&ctx, watermark, &config, "get_watermark_from_backend", &cx).await?);
     }
+    if let Some(overlay) = &options.overlay {
+        check_overlay_url(overlay, &route, &config)?;
+        debug!("fetching overlay from backend: {}", overlay);
+        overlays.overlay =
+            Some(fetch_overlay(&ctx, overlay, &config, "get_overlay_from_backend", &cx).await?);
+    }
+
+    let permit = match &ctx.processing {
+        Some(semaphore) => {
+            let timeout = Duration::from_millis(config.processing_queue_timeout_ms);
+            match tokio::time::timeout(timeout, semaphore.clone().acquire_owned()).await {
+                Ok(Ok(permit)) => Some(permit),
+                _ => {
+                    metrics::record_processing_rejected();
+                    return Err(error::Error::Overloaded);
+                }
+            }
+        }
+        None => None,
+    };
 
     debug!("processing image: {}", target);
     let (send, recv) = tokio::sync::oneshot::channel();
+    let service = ctx.clone();
     rayon::spawn(move || {
+        // Held until processing finishes so the permit covers the libvips work
+        let _permit = permit;
         let span = global::tracer("shrinkray").start_with_context("process_image", &cx);
         let cx = TraceContext::current_with_span(span);
-        let image = image::process_image(&image, &mut options, &ctx.config, &cx)
-            .map_err(|err| ctx.vips_error(err));
+        let start = Instant::now();
+        // The header is read once here, off the async workers, and reused for the
+        // checks and processing
+        let image = image::SourceHeader::read(&image)
+            .map_err(|err| {
+                // Error pages and other non-images have no loader
+                let _ = service.vips_error(err);
+                error::Error::UnsupportedMediaType
+            })
+            .and_then(|header| {
+                check_source(&header, &options, &config)?;
+                image::process_source(&image, &header, &overlays, &mut options, &config, &cx)
+                    .map_err(|err| service.vips_error(err))
+            });
+        if let Ok(image) = &image {
+            metrics::record_process_duration(image.content_type, start.elapsed());
+        }
         let _ = send.send(image);
     });
     let image = recv
@@ -120,22 +478,132 @@ async fn handle_image_request(
             });
         })?;
 
+    let image = if let Some(key) = &cache_key {
+        let entry = cache::Entry {
+            image,
+            last_modified,
+        };
+        ctx.cache.put(key, &entry).await;
+        entry.image
+    } else {
+        image
+    };
+
     span.set_status(Status::Ok);
-    Ok((get_headers(&image, download)?, image.bytes))
+    metrics::record_output_bytes(Some(image.content_type), image.bytes.len());
+    get_response(
+        &request_headers,
+        get_headers(
+            image.content_type.content_type(),
+            image.bytes.len(),
+            download,
+            last_modified,
+            etag.as_deref(),
+            &vary,
+            cache_control,
+        )?,
+        image.bytes,
+    )
+    .map(|response| with_status(response, fallback_status))
+}
+
+/// Reject watermark and overlay URLs outside the route's endpoint and `overlay_hosts`.
+fn check_overlay_url(
+    url: &str,
+    route: &config::ConfigRouting,
+    config: &config::Config,
+) -> Result<()> {
+    let parsed = url::Url::parse(url)
+        .map_err(|_| error::Error::BadRequest(format!("invalid image url `{url}`")))?;
+    if backend::overlay_allowed(&parsed, &route.endpoint, &config.overlay_hosts) {
+        Ok(())
+    } else {
+        Err(error::Error::BadRequest(format!(
+            "image url `{url}` is not allowed"
+        )))
+    }
+}
+
+/// Fetch an extra image to composite onto the source. Route backend headers
+/// aren't sent, since the URL comes from the request.
+async fn fetch_overlay(
+    ctx: &Service,
+    url: &str,
+    config: &config::Config,
+    span_name: &'static str,
+    cx: &TraceContext,
+) -> Result<Vec<u8>> {
+    let mut span = global::tracer("shrinkray").start_with_context(span_name, cx);
+    let result = ctx
+        .fetch(url, config, HeaderMap::new())
+        .await
+        .inspect_err(|err| {
+            span.set_status(Status::Error {
+                description: err.to_string().into(),
+            });
+        });
+    span.end();
+    // The URL comes from the request, so one that can't be fetched is the client's mistake
+    match result {
+        Ok(file) => Ok(file.bytes),
+        Err(error::Error::UrlParse(_) | error::Error::InvalidBackend) => Err(
+            error::Error::BadRequest(format!("invalid image url `{url}`")),
+        ),
+        Err(err) => Err(err),
+    }
+}
+
+/// Move transformations in the first path segment into the query string, so the
+/// request is handled and signed like the equivalent query string URL.
+fn apply_path_options(
+    route: &config::ConfigRouting,
+    request_path: String,
+    uri: Uri,
+) -> Result<(String, Uri)> {
+    let prefix = format!("/{}", &route.path[..route.path.find('{').unwrap_or(0)]);
+    let rewritten = path_options::rewrite(
+        &request_path,
+        uri.path(),
+        uri.query().unwrap_or_default(),
+        &prefix,
+    )
+    .map_err(error::Error::BadRequest)?;
+    match rewritten {
+        Some((request_path, path_and_query)) => {
+            let uri = path_and_query
+                .parse()
+                .map_err(|_| error::Error::BadRequest("invalid path".to_string()))?;
+            Ok((request_path, uri))
+        }
+        None => Ok((request_path, uri)),
+    }
 }
 
-fn get_router(config: &'static config::Config) -> Router<Arc<Service>> {
+/// Replace the status of a complete response, leaving range responses alone.
+fn with_status(mut response: Response, status: Option<StatusCode>) -> Response {
+    if let Some(status) = status
+        && response.status() == StatusCode::OK
+    {
+        *response.status_mut() = status;
+    }
+    response
+}
+
+fn get_router(config: &config::Config) -> Router<Arc<Service>> {
     let mut router: Router<Arc<Service>> =
         Router::new().route("/favicon.ico", get(|| async { StatusCode::NOT_FOUND }));
 
     for route in &config.routing {
         let path = format!("/{}", &route.path);
-        let endpoint = route.endpoint.clone();
-        let route_path = route.path.clone();
+        let route = route.clone();
 
         let handler = move |ctx: State<Arc<Service>>,
                             Path(request_path): Path<String>,
-                            options: Query<options::ImageOptions>,
+                            uri: Uri,
+                            options: std::result::Result<
+            Query<options::ImageOptions>,
+            QueryRejection,
+        >,
                             headers: HeaderMap| {
             async move {
                 let scope = InstrumentationScope::builder("basic")
@@ -143,64 +611,234 @@ fn get_router(config: &'static config::Config) -> Router<Arc<Service>> {
                     .build();
                 let tracer = global::tracer_with_scope(scope.clone());
 
-                let mut span = tracer.start("handle_image_request");
+                let parent = global::get_text_map_propagator(|propagator| {
+                    propagator.extract(&http::HeaderExtractor(&headers))
+                });
+                let mut span = tracer.start_with_context("handle_image_request", &parent);
                 span.set_attributes([
                     KeyValue::new("shrinkray.request_path", request_path.clone()),
-                    KeyValue::new("shrinkray.endpoint", endpoint.clone()),
-                    KeyValue::new("shrinkray.route_path", route_path.clone()),
+                    KeyValue::new("shrinkray.endpoint", route.endpoint.clone()),
+                    KeyValue::new("shrinkray.route_path", route.path.clone()),
                 ]);
-                let cx = TraceContext::current_with_span(span);
-
-                handle_image_request(
-                    ctx,
-                    request_path,
-                    options,
-                    headers,
-                    endpoint,
-                    route_path,
-                    cx,
-                )
-                .await
+                if let Some(request_id) = headers
+                    .get(&request_id::X_REQUEST_ID)
+                    .and_then(|value| value.to_str().ok())
+                {
+                    span.set_attribute(KeyValue::new(
+                        "shrinkray.request_id",
+                        request_id.to_string(),
+                    ));
+                }
+                let cx = parent.with_span(span);
+
+                let json_errors = headers.accepts("application/json");
+                let result = async {
+                    let (request_path, uri, options) = if route.path_options {
+                        let (request_path, uri) = apply_path_options(&route, request_path, uri)?;
+                        let options = Query::try_from_uri(&uri);
+                        (request_path, uri, options)
+                    } else {
+                        (request_path, uri, options)
+                    };
+                    // Invalid options are the client's mistake, not a server error
+                    let options = options
+                        .map_err(|rejection| error::Error::BadRequest(rejection.body_text()))?;
+                    handle_image_request(ctx, request_path, uri, options, headers, route, cx).await
+                }
+                .await;
+                match result {
+                    Ok(response) => response,
+                    Err(err) if json_errors => err.into_json_response(),
+                    Err(err) => err.into_response(),
+                }
             }
         };
 
+        // `get` also answers HEAD with the same headers, including the encoded
+        // length, and an empty body
         router = router.route(&path, get(handler));
     }
 
     router
 }
 
+/// Build the routes for a configuration, ready to serve requests.
+fn get_routes(config: &config::Config, service: &Arc<Service>) -> Router {
+    get_router(config)
+        .route_layer(middleware::from_fn(metrics::middleware))
+        .with_state(service.clone())
+}
+
+/// Re-read the configuration on SIGHUP and swap in the new routes. If the file
+/// can't be read the current configuration is kept.
+#[cfg(unix)]
+fn reload_on_hangup(service: Arc<Service>, routes: Arc<RwLock<Router>>) {
+    tokio::spawn(async move {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("failed to create hangup handler");
+        while hangup.recv().await.is_some() {
+            match read_config() {
+                Ok(config) => {
+                    let router = get_routes(&config, &service);
+                    service.set_config(config);
+                    *routes.write().unwrap_or_else(PoisonError::into_inner) = router;
+                    info!("reloaded configuration");
+                }
+                Err(err) => {
+                    error!("failed to reload configuration, keeping current: {}", err);
+                }
+            }
+        }
+    });
+}
+
+/// Request each `warmup` path through the routes in the background, so the
+/// results are cached before clients ask for them. Failures are only logged.
+fn warm_up(service: &Arc<Service>) {
+    let config = service.config();
+    if config.warmup.is_empty() {
+        return;
+    }
+    if service.cache.is_empty() {
+        warn!("skipping warmup because no cache is configured");
+        return;
+    }
+    let routes = get_routes(&config, service);
+    tokio::spawn(async move {
+        for path in &config.warmup {
+            let request = match Request::get(path).body(axum::body::Body::empty()) {
+                Ok(request) => request,
+                Err(err) => {
+                    warn!("failed to warm up {}: {}", path, err);
+                    continue;
+                }
+            };
+            match routes.clone().oneshot(request).await {
+                Ok(response) if response.status().is_success() => {
+                    debug!("warmed up {}", path);
+                }
+                Ok(response) => warn!("failed to warm up {}: {}", path, response.status()),
+                Err(err) => warn!("failed to warm up {}: {}", path, err),
+            }
+        }
+        info!("finished warming up {} paths", config.warmup.len());
+    });
+}
+
 async fn run_server(
     service: &Arc<service::Service>,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let config = service.config.clone();
-    let router = get_router(Box::leak(Box::new(config)))
-        .route_layer(middleware::from_fn(metrics::middleware))
+    let config = service.config();
+    let routes = Arc::new(RwLock::new(get_routes(&config, service)));
+
+    #[cfg(unix)]
+    reload_on_hangup(service.clone(), routes.clone());
+
+    // Dispatch through the current routes so they can be replaced on reload
+    let router = Router::new()
+        .fallback_service(service_fn(move |req: Request| {
+            let routes = routes
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone();
+            routes.oneshot(req)
+        }))
+        .layer(middleware::from_fn_with_state(
+            service.clone(),
+            ratelimit::middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            service.clone(),
+            cors::middleware,
+        ))
         .layer(middleware::from_fn_with_state(
             service.clone(),
             logging::middleware,
         ))
-        .with_state(service.clone());
+        .layer(middleware::from_fn(request_id::middleware));
 
-    let listener = tokio::net::TcpListener::bind(&service.config.server_address).await?;
+    // Load the certificate before listening so a bad one fails startup
+    let acceptor = config.tls.as_ref().map(tls::acceptor).transpose()?;
+    let listener = tokio::net::TcpListener::bind(&config.server_address).await?;
     debug!("listening on {}", &listener.local_addr()?);
+    service.set_ready(true);
 
-    axum::serve(listener, router)
-        .with_graceful_shutdown(service::shutdown())
-        .await?;
+    let ctx = service.clone();
+    let shutdown = async move {
+        service::shutdown().await;
+        ctx.set_ready(false);
+    };
+    let make_service = router.into_make_service_with_connect_info::<SocketAddr>();
+    match acceptor {
+        Some(acceptor) => {
+            // Tapping the listener provides the remote address as connect info
+            let listener = tls::TlsListener::new(listener, acceptor).tap_io(|_| {});
+            axum::serve(listener, make_service)
+                .with_graceful_shutdown(shutdown)
+                .await?;
+        }
+        None => {
+            axum::serve(listener, make_service)
+                .with_graceful_shutdown(shutdown)
+                .await?;
+        }
+    }
     Ok(())
 }
 
+#[derive(serde::Deserialize)]
+struct HealthQuery {
+    #[serde(default)]
+    deep: Option<String>,
+}
+
+/// Liveness check. With `?deep=1` an embedded image is also processed so that a
+/// broken libvips install reports as unhealthy.
+async fn health(
+    State(ctx): State<Arc<service::Service>>,
+    Query(query): Query<HealthQuery>,
+) -> StatusCode {
+    if !matches!(query.deep.as_deref(), Some("1" | "true")) {
+        return StatusCode::OK;
+    }
+
+    let (send, recv) = tokio::sync::oneshot::channel();
+    let config = ctx.config();
+    rayon::spawn(move || {
+        let _ = send.send(image::health_check(&config, &TraceContext::new()));
+    });
+    match recv.await {
+        Ok(Ok(())) => StatusCode::OK,
+        Ok(Err(err)) => {
+            error!("deep health check failed: {}", err);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+/// Readiness check, failing until the server is listening and again once it
+/// starts shutting down.
+async fn readiness(State(ctx): State<Arc<service::Service>>) -> StatusCode {
+    if ctx.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
 async fn run_management_server(
     service: &Arc<service::Service>,
 ) -> std::result::Result<(), Box<dyn std::error::Error>> {
     let prom_handle = metrics::setup_metrics();
     let router = Router::new()
         .route("/metrics", get(move || ready(prom_handle.render())))
-        .route("/healthz", get(|| async { StatusCode::OK }));
+        .route("/healthz", get(health))
+        .route("/readyz", get(readiness))
+        .with_state(service.clone());
 
     let listener: tokio::net::TcpListener =
-        tokio::net::TcpListener::bind(&service.config.management_address).await?;
+        tokio::net::TcpListener::bind(&service.config().management_address).await?;
     debug!("management listening on {}", &listener.local_addr()?);
     axum::serve(listener, router).await?;
 
@@ -209,19 +847,45 @@ async fn run_management_server(
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() {
-    otel::setup_logging();
-
-    let config = read_config();
-    if config.is_err() {
-        eprintln!("failed to read configuration: {}", config.unwrap_err());
-        std::process::exit(1);
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("sign") => {
+            match cli::sign(&args[2..]) {
+                Ok(url) => println!("{url}"),
+                Err(err) => {
+                    eprintln!("failed to sign url: {err}");
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some("process") => {
+            if let Err(err) = cli::process(&args[2..]) {
+                eprintln!("failed to process image: {err}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => {}
     }
 
-    let service = Arc::new(Service::new(config.unwrap()));
+    let config = match read_config() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("failed to read configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+    otel::setup_logging(config.log_format);
 
-    let tracer_provider = otel::setup_tracing(&service.config);
+    let service = Arc::new(Service::new(config));
+
+    let tracer_provider = otel::setup_tracing(&service.config());
 
     global::set_tracer_provider(tracer_provider.clone());
+    let meter_provider = otel::setup_metrics_otel(&service.config());
+
+    warm_up(&service);
 
     let service_clone = service.clone();
     tokio::spawn(async move {
@@ -235,4 +899,144 @@ async fn main() {
     tracer_provider
         .shutdown()
         .expect("failed to shutdown tracer provider");
+    if let Some(meter_provider) = meter_provider {
+        meter_provider
+            .shutdown()
+            .expect("failed to shutdown meter provider");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{HeaderName, Method, Request};
+    use rstest::rstest;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const LAST_MODIFIED: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    /// Serve `bytes` for every path from a local origin, returning its URL and
+    /// a count of the requests it has answered
+    async fn mock_origin(bytes: &'static [u8]) -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let router = Router::new().fallback(move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            ([(header::LAST_MODIFIED, LAST_MODIFIED)], bytes)
+        });
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        (format!("http://{addr}/"), requests)
+    }
+
+    fn mock_routes(endpoint: &str, config: config::Config) -> Router {
+        let config = config::Config {
+            routing: vec![config::ConfigRouting {
+                path: "{*path}".to_string(),
+                endpoint: endpoint.to_string(),
+                signing_secret: None,
+                backend_headers: config::BackendHeaders::default(),
+                cache_control: None,
+                fallback_image: None,
+                fallback_status: None,
+                path_options: false,
+                weak_etag: false,
+            }],
+            ..config
+        };
+        let service = Arc::new(Service::new(config.clone()));
+        get_routes(&config, &service)
+    }
+
+    async fn send(
+        routes: &Router,
+        method: Method,
+        uri: &str,
+        headers: &[(HeaderName, &str)],
+    ) -> Response {
+        let mut request = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        routes
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cached_not_modified() {
+        let (endpoint, requests) =
+            mock_origin(include_bytes!("../tests/fixtures/animated.gif")).await;
+        let routes = mock_routes(
+            &endpoint,
+            config::Config {
+                lru_max_bytes: Some(1 << 20),
+                ..config::Config::default()
+            },
+        );
+        let uri = "/animated.gif?w=10&fm=png";
+        let response = send(&routes, Method::GET, uri, &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::LAST_MODIFIED], LAST_MODIFIED);
+
+        // The revalidation is answered from the cache without asking the origin
+        let response = send(
+            &routes,
+            Method::GET,
+            uri,
+            &[(header::IF_MODIFIED_SINCE, LAST_MODIFIED)],
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::LAST_MODIFIED], LAST_MODIFIED);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[rstest]
+    #[case::processed("/animated.gif?w=10&fm=png")]
+    #[case::passthrough("/animated.gif")]
+    #[tokio::test]
+    async fn test_head_matches_get(#[case] uri: &str) {
+        let (endpoint, _) = mock_origin(include_bytes!("../tests/fixtures/animated.gif")).await;
+        let routes = mock_routes(&endpoint, config::Config::default());
+        let get = send(&routes, Method::GET, uri, &[]).await;
+        let head = send(&routes, Method::HEAD, uri, &[]).await;
+        assert_eq!(get.status(), StatusCode::OK);
+        assert_eq!(head.status(), StatusCode::OK);
+        for name in [header::CONTENT_TYPE, header::CONTENT_LENGTH] {
+            assert_eq!(
+                head.headers().get(&name),
+                get.headers().get(&name),
+                "{name}"
+            );
+        }
+        let body = axum::body::to_bytes(head.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_svg_passthrough_sandboxed() {
+        let (endpoint, _) = mock_origin(
+            b"<svg xmlns=\"http://www.w3.org/2000/svg\"><script>alert(1)</script></svg>",
+        )
+        .await;
+        let routes = mock_routes(&endpoint, config::Config::default());
+        let response = send(&routes, Method::GET, "/logo.svg", &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers[header::CONTENT_TYPE], "image/svg+xml");
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert!(
+            headers[header::CONTENT_SECURITY_POLICY]
+                .to_str()
+                .unwrap()
+                .contains("sandbox")
+        );
+    }
 }
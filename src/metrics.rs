@@ -1,5 +1,13 @@
-use axum::{extract::Request, http::StatusCode, middleware::Next, response::IntoResponse};
+use crate::circuit::CircuitState;
+use crate::options::ImageFormat;
+use axum::{
+    extract::MatchedPath, extract::Request, http::StatusCode, middleware::Next,
+    response::IntoResponse,
+};
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::{KeyValue, global};
+use std::sync::OnceLock;
 use std::time::{Duration, Instant};
 
 const BUCKET_VALUES: &[f64] = &[
@@ -23,11 +31,115 @@ pub fn setup_metrics() -> PrometheusHandle {
         )
         .expect("error creating metric bucket");
 
+    builder = builder
+        .set_buckets_for_metric(
+            Matcher::Full("shrinkray_process_duration_seconds".to_string()),
+            BUCKET_VALUES,
+        )
+        .expect("error creating metric bucket");
+
     builder
         .install_recorder()
         .expect("error installing prometheus recorder")
 }
 
+/// OpenTelemetry mirrors of the key Prometheus metrics, exported when a
+/// collector is configured and no-ops otherwise.
+struct OtelInstruments {
+    responses: Counter<u64>,
+    response_duration: Histogram<f64>,
+    process_duration: Histogram<f64>,
+    cache_lookups: Counter<u64>,
+    source_bytes: Counter<u64>,
+    output_bytes: Counter<u64>,
+}
+
+fn otel() -> &'static OtelInstruments {
+    // Created on first use, after the meter provider has been installed
+    static INSTRUMENTS: OnceLock<OtelInstruments> = OnceLock::new();
+    INSTRUMENTS.get_or_init(|| {
+        let meter = global::meter("shrinkray");
+        OtelInstruments {
+            responses: meter.u64_counter("shrinkray.http.responses").build(),
+            response_duration: meter
+                .f64_histogram("shrinkray.http.response.duration")
+                .with_unit("s")
+                .with_boundaries(BUCKET_VALUES.to_vec())
+                .build(),
+            process_duration: meter
+                .f64_histogram("shrinkray.process.duration")
+                .with_unit("s")
+                .with_boundaries(BUCKET_VALUES.to_vec())
+                .build(),
+            cache_lookups: meter.u64_counter("shrinkray.cache.lookups").build(),
+            source_bytes: meter
+                .u64_counter("shrinkray.source.bytes")
+                .with_unit("By")
+                .build(),
+            output_bytes: meter
+                .u64_counter("shrinkray.output.bytes")
+                .with_unit("By")
+                .build(),
+        }
+    })
+}
+
+pub fn record_cache_lookup(hit: bool) {
+    otel().cache_lookups.add(1, &[KeyValue::new("hit", hit)]);
+    if hit {
+        metrics::counter!("shrinkray_cache_hits_total").increment(1);
+    } else {
+        metrics::counter!("shrinkray_cache_misses_total").increment(1);
+    }
+}
+
+pub fn record_backend_retry() {
+    metrics::counter!("shrinkray_backend_retries_total").increment(1);
+}
+
+pub fn record_process_duration(format: ImageFormat, elapsed: Duration) {
+    otel().process_duration.record(
+        elapsed.as_secs_f64(),
+        &[KeyValue::new("format", format.to_string())],
+    );
+    metrics::histogram!("shrinkray_process_duration_seconds", "format" => format.to_string())
+        .record(elapsed.as_secs_f64());
+}
+
+pub fn record_source_bytes(bytes: usize) {
+    otel().source_bytes.add(bytes as u64, &[]);
+    metrics::counter!("shrinkray_source_bytes_total").increment(bytes as u64);
+}
+
+/// Record the size of a response, labelled by its format, or `other` for
+/// originals served unchanged in a format that can't be written.
+pub fn record_output_bytes(format: Option<ImageFormat>, bytes: usize) {
+    let format: &'static str = format.map_or("other", Into::into);
+    otel()
+        .output_bytes
+        .add(bytes as u64, &[KeyValue::new("format", format)]);
+    metrics::counter!("shrinkray_output_bytes_total", "format" => format).increment(bytes as u64);
+}
+
+pub fn record_processing_rejected() {
+    metrics::counter!("shrinkray_processing_rejected_total").increment(1);
+}
+
+pub fn record_circuit_state(host: &str, state: CircuitState) {
+    let value = match state {
+        CircuitState::Closed => 0.0,
+        CircuitState::Open => 1.0,
+        CircuitState::HalfOpen => 2.0,
+    };
+    metrics::gauge!("shrinkray_backend_circuit_state", "host" => host.to_string()).set(value);
+}
+
+#[allow(clippy::cast_precision_loss)]
+pub fn record_cache_size(entries: usize, bytes: usize) {
+    metrics::gauge!("shrinkray_cache_entries").set(entries as f64);
+    metrics::gauge!("shrinkray_cache_bytes").set(bytes as f64);
+}
+
 pub async fn middleware(req: Request, next: Next) -> impl IntoResponse {
     let start = Instant::now();
     let uri = req.uri().to_string();
@@ -36,21 +148,42 @@ pub async fn middleware(req: Request, next: Next) -> impl IntoResponse {
         return next.run(req).await;
     }
 
+    // Label by the configured route rather than the request path to keep cardinality bounded
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().trim_start_matches('/').to_string())
+        .unwrap_or_default();
+
     let response = next.run(req).await;
-    match response.status() {
+    let status = response.status();
+    let attributes = [
+        KeyValue::new("route", route.clone()),
+        KeyValue::new("status", i64::from(status.as_u16())),
+    ];
+    otel().responses.add(1, &attributes);
+    otel()
+        .response_duration
+        .record(start.elapsed().as_secs_f64(), &attributes);
+
+    match status {
         StatusCode::OK => {
-            metrics::counter!("shrinkray_http_response_200").increment(1);
+            metrics::counter!("shrinkray_http_response_200", "route" => route.clone()).increment(1);
             let elapsed = start.elapsed().as_secs_f64();
-            metrics::histogram!("shrinkray_http_response_seconds_bucket").record(elapsed);
+            metrics::histogram!("shrinkray_http_response_seconds_bucket", "route" => route)
+                .record(elapsed);
         }
         StatusCode::UNAUTHORIZED => {
-            metrics::counter!("shrinkray_http_response_401").increment(1);
+            metrics::counter!("shrinkray_http_response_401", "route" => route).increment(1);
         }
         StatusCode::NOT_FOUND => {
-            metrics::counter!("shrinkray_http_response_404").increment(1);
+            metrics::counter!("shrinkray_http_response_404", "route" => route).increment(1);
         }
         StatusCode::INTERNAL_SERVER_ERROR => {
-            metrics::counter!("shrinkray_http_response_500").increment(1);
+            metrics::counter!("shrinkray_http_response_500", "route" => route).increment(1);
+        }
+        StatusCode::SERVICE_UNAVAILABLE => {
+            metrics::counter!("shrinkray_http_response_503", "route" => route).increment(1);
         }
         _ => {}
     }
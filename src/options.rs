@@ -1,11 +1,14 @@
+use crate::config::SigningAlgorithm;
+use axum::extract::Query;
+use axum::http::Uri;
 use libvips::ops;
 use ring::hmac;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::cmp::PartialOrd;
 use std::collections::BTreeMap;
 use std::fmt::Display;
-use std::ops::{Div, Mul};
-use strum::Display;
+use std::ops::{Div, Mul, RangeInclusive};
+use strum::{Display, EnumString, IntoStaticStr};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -13,6 +16,14 @@ pub struct ImageOptions {
     #[serde(default, rename = "sig", skip_serializing_if = "Option::is_none")]
     pub signature: Option<String>,
 
+    /// Unix timestamp after which a signed URL is no longer valid
+    #[serde(default, rename = "exp", skip_serializing_if = "Option::is_none")]
+    pub expires: Option<i64>,
+
+    /// Named set of parameters from the config, which explicit parameters override
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+
     /// Background color
     #[serde(
         default,
@@ -35,13 +46,22 @@ pub struct ImageOptions {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub lossless: Option<bool>,
 
+    /// Progressive (interlaced) JPEG output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progressive: Option<bool>,
+
     /// Quality
     #[serde(default, rename = "q", skip_serializing_if = "Option::is_none")]
     pub quality: Option<i32>,
 
     /// Device Pixel Ratio
-    #[serde(default, rename = "dpr", skip_serializing_if = "Option::is_none")]
-    pub device_pixel_ratio: Option<i32>,
+    #[serde(
+        default,
+        rename = "dpr",
+        deserialize_with = "deserialize_device_pixel_ratio",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub device_pixel_ratio: Option<f64>,
 
     /// Rotation
     #[serde(
@@ -52,6 +72,14 @@ pub struct ImageOptions {
     )]
     pub rotate: Option<Rotation>,
 
+    /// Flip vertically
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flip: Option<bool>,
+
+    /// Flip horizontally
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flop: Option<bool>,
+
     /// Width
     #[serde(
         default,
@@ -70,10 +98,58 @@ pub struct ImageOptions {
     )]
     pub height: Option<i32>,
 
+    /// Page of a PDF document, starting from 0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<i32>,
+
+    /// Frame of an animation to extract as a still image, starting from 0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame: Option<i32>,
+
+    /// Rendering resolution for PDF documents
+    #[serde(
+        default,
+        deserialize_with = "deserialize_dpi",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub dpi: Option<i32>,
+
+    /// Rasterisation density for SVG images, in DPI
+    #[serde(
+        default,
+        deserialize_with = "deserialize_dpi",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub density: Option<i32>,
+
+    /// Colour profile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<Profile>,
+
+    /// Strip EXIF, XMP and IPTC metadata (default: true)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip: Option<bool>,
+
     /// Fit
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub fit: Option<Fit>,
 
+    /// Crop gravity
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gravity: Option<Gravity>,
+
+    /// Content-aware crop strategy for `fit=crop`, or an exact `x,y,w,h` rectangle
+    #[serde(
+        default,
+        deserialize_with = "deserialize_crop",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub crop: Option<Crop>,
+
+    /// Resampling kernel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<Kernel>,
+
     /// Image format
     #[serde(default, rename = "fm", skip_serializing_if = "Option::is_none")]
     pub format: Option<ImageFormat>,
@@ -93,19 +169,255 @@ pub struct ImageOptions {
         skip_serializing_if = "Option::is_none"
     )]
     pub trim_colour: Option<Colour>,
-    //pub heif_effort: i32,
-    //pub heif_encoder: Encoder,
 
-    // Sharpen
+    /// How far a pixel can differ from the background and still be trimmed (default: 40)
+    #[serde(
+        default,
+        rename = "trim-threshold",
+        deserialize_with = "deserialize_trim_threshold",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub trim_threshold: Option<f64>,
+
+    /// Trim line art without smoothing, so thin lines near the edges are kept
+    #[serde(
+        default,
+        rename = "trim-lineart",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub trim_lineart: Option<bool>,
+
+    /// AVIF encoding effort, 0 (fastest) to 9 (smallest)
+    #[serde(
+        default,
+        deserialize_with = "deserialize_effort",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub effort: Option<i32>,
+
+    /// AVIF encoder
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoder: Option<Encoder>,
+
+    /// AVIF bit depth, 8, 10 or 12. Ignored for other formats.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_bitdepth",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub bitdepth: Option<i32>,
+
+    /// Return the source image's metadata as JSON instead of processing it
+    #[serde(
+        default,
+        deserialize_with = "deserialize_flag",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta: Option<bool>,
+
+    /// Horizontal BlurHash components for `fm=blurhash`, 1 to 9 (default: 4)
+    #[serde(
+        default,
+        rename = "bh-x",
+        deserialize_with = "deserialize_blurhash_components",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub blurhash_x: Option<u32>,
+
+    /// Vertical BlurHash components for `fm=blurhash`, 1 to 9 (default: 3)
+    #[serde(
+        default,
+        rename = "bh-y",
+        deserialize_with = "deserialize_blurhash_components",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub blurhash_y: Option<u32>,
+
+    // Sharpen
+    #[serde(
+        default,
+        deserialize_with = "deserialize_strength",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub sharpen: Option<Percentage>,
 
+    /// Unsharp mask radius, the sigma of the Gaussian, replacing the one derived from `sharpen`
+    #[serde(
+        default,
+        rename = "sharpen-radius",
+        deserialize_with = "deserialize_sharpen_radius",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub sharpen_radius: Option<f64>,
+
+    /// Unsharp mask amount, the slope applied to edges
+    #[serde(
+        default,
+        rename = "sharpen-amount",
+        deserialize_with = "deserialize_sharpen_amount",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub sharpen_amount: Option<f64>,
+
     // Blur
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_strength",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub blur: Option<Percentage>,
 
-    // Filters
+    /// Brightness (-100 to 100)
+    #[serde(
+        default,
+        rename = "bri",
+        deserialize_with = "deserialize_signed_percentage",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub brightness: Option<Percentage>,
+
+    /// Contrast (-100 to 100)
+    #[serde(
+        default,
+        rename = "con",
+        deserialize_with = "deserialize_signed_percentage",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub contrast: Option<Percentage>,
+
+    /// Saturation (0 = greyscale, 100 = unchanged, up to 300)
+    #[serde(
+        default,
+        rename = "sat",
+        deserialize_with = "deserialize_saturation",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub saturation: Option<Percentage>,
+
+    /// Hue rotation in degrees (0 to 360)
+    #[serde(
+        default,
+        deserialize_with = "deserialize_hue",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub hue: Option<i32>,
+
+    /// Watermark image URL
+    #[serde(default, rename = "wm", skip_serializing_if = "Option::is_none")]
+    pub watermark: Option<String>,
+
+    /// Watermark position
+    #[serde(
+        default,
+        rename = "wm-gravity",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub watermark_gravity: Option<Gravity>,
+
+    /// Watermark opacity
+    #[serde(
+        default,
+        rename = "wm-opacity",
+        deserialize_with = "deserialize_percentage",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub watermark_opacity: Option<Percentage>,
+
+    /// Watermark width as a percentage of the image width
+    #[serde(
+        default,
+        rename = "wm-scale",
+        deserialize_with = "deserialize_percentage",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub watermark_scale: Option<Percentage>,
+
+    /// Overlay image URL, composited at `overlay-x` and `overlay-y`
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overlay: Option<String>,
+
+    /// Overlay offset from the left edge in pixels
+    #[serde(default, rename = "overlay-x", skip_serializing_if = "Option::is_none")]
+    pub overlay_x: Option<i32>,
+
+    /// Overlay offset from the top edge in pixels
+    #[serde(default, rename = "overlay-y", skip_serializing_if = "Option::is_none")]
+    pub overlay_y: Option<i32>,
+
+    /// How the overlay is blended with the image
+    #[serde(
+        default,
+        rename = "overlay-blend",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub overlay_blend: Option<BlendMode>,
+
+    /// Text overlay
+    #[serde(
+        default,
+        rename = "txt",
+        deserialize_with = "deserialize_text",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub text: Option<String>,
+
+    /// Text colour
+    #[serde(
+        default,
+        rename = "txt-colour",
+        deserialize_with = "deserialize_colour",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub text_colour: Option<Colour>,
+
+    /// Text size in pixels
+    #[serde(
+        default,
+        rename = "txt-size",
+        deserialize_with = "deserialize_text_size",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub text_size: Option<i32>,
+
+    /// Text position
+    #[serde(
+        default,
+        rename = "txt-gravity",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub text_gravity: Option<Gravity>,
+
+    /// Border width in pixels, added outside the requested dimensions
+    #[serde(
+        default,
+        deserialize_with = "deserialize_dimension",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub border: Option<i32>,
+
+    /// Border colour
+    #[serde(
+        default,
+        rename = "border-colour",
+        deserialize_with = "deserialize_colour",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub border_colour: Option<Colour>,
+
+    /// Corner radius in pixels, or as a percentage of the shorter edge (e.g. `10%`)
+    #[serde(
+        default,
+        deserialize_with = "deserialize_radius",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub radius: Option<Radius>,
+
+    // Filters
+    #[serde(
+        default,
+        deserialize_with = "deserialize_percentage",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub kodachrome: Option<Percentage>,
 
     #[serde(
@@ -142,6 +454,67 @@ pub struct ImageOptions {
         skip_serializing_if = "Option::is_none"
     )]
     pub monochrome: Option<Percentage>,
+
+    /// Convert to a true luminance greyscale, unlike the stylised `monochrome`
+    #[serde(default, alias = "grayscale", skip_serializing_if = "Option::is_none")]
+    pub greyscale: Option<bool>,
+
+    /// Tint colour, replacing the hue and saturation while keeping the luminance
+    #[serde(
+        default,
+        deserialize_with = "deserialize_colour",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tint: Option<Colour>,
+
+    /// Tint strength
+    #[serde(
+        default,
+        rename = "tint-opacity",
+        deserialize_with = "deserialize_percentage",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tint_opacity: Option<Percentage>,
+
+    /// Invert the colours, after any other filters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub negate: Option<bool>,
+
+    /// Stretch each channel to the full range, before any other filters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub autolevel: Option<bool>,
+
+    /// Reduce each colour channel to this many levels, 2 to 256
+    #[serde(
+        default,
+        deserialize_with = "deserialize_posterize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub posterize: Option<i32>,
+
+    /// Convert to black and white, with pixels at or above this luminance becoming white
+    #[serde(
+        default,
+        deserialize_with = "deserialize_percentage",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub threshold: Option<Percentage>,
+
+    /// Darken the edges of the image
+    #[serde(
+        default,
+        deserialize_with = "deserialize_percentage",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub vignette: Option<Percentage>,
+
+    /// Pixelate with blocks of this many pixels in the output image
+    #[serde(
+        default,
+        deserialize_with = "deserialize_dimension",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pixelate: Option<i32>,
 }
 
 impl Default for ImageOptions {
@@ -152,25 +525,75 @@ impl Default for ImageOptions {
             quality: None,
             aspect_ratio: None,
             download: None,
+            expires: None,
+            preset: None,
             trim: None,
             trim_colour: None,
+            trim_threshold: None,
+            trim_lineart: None,
             sharpen: None,
+            sharpen_radius: None,
+            sharpen_amount: None,
             blur: None,
+            brightness: None,
+            contrast: None,
+            saturation: None,
+            hue: None,
             kodachrome: None,
             technicolor: None,
             vintage: None,
             polaroid: None,
             sepia: None,
             monochrome: None,
+            greyscale: None,
+            tint: None,
+            tint_opacity: None,
+            negate: None,
+            autolevel: None,
+            pixelate: None,
+            vignette: None,
+            posterize: None,
+            threshold: None,
             width: None,
             height: None,
-            device_pixel_ratio: Some(1),
+            device_pixel_ratio: Some(1.0),
             rotate: None,
+            flip: None,
+            flop: None,
             format: None,
-            //heif_effort: 6, // 0-6
-            //heif_encoder: Encoder::Rav1E,
+            effort: None,
+            encoder: None,
+            bitdepth: None,
+            meta: None,
+            blurhash_x: None,
+            blurhash_y: None,
             lossless: None,
+            progressive: None,
+            page: None,
+            frame: None,
+            dpi: None,
+            density: None,
+            profile: None,
+            strip: None,
             fit: None,
+            gravity: None,
+            crop: None,
+            kernel: None,
+            watermark: None,
+            watermark_gravity: None,
+            watermark_opacity: None,
+            watermark_scale: None,
+            overlay: None,
+            overlay_x: None,
+            overlay_y: None,
+            overlay_blend: None,
+            text: None,
+            text_colour: None,
+            text_size: None,
+            text_gravity: None,
+            border: None,
+            border_colour: None,
+            radius: None,
         }
     }
 }
@@ -186,23 +609,75 @@ impl ImageOptions {
             || (self.quality.is_some() && self.quality.unwrap() != 75)
             || self.aspect_ratio.is_some()
             || self.download.is_some()
+            || self.expires.is_some()
+            || self.preset.is_some()
             || self.trim.is_some()
             || self.trim_colour.is_some()
+            || self.trim_threshold.is_some()
+            || self.trim_lineart.is_some()
             || self.sharpen.is_some()
+            || self.sharpen_radius.is_some()
+            || self.sharpen_amount.is_some()
             || self.blur.is_some()
+            || self.brightness.is_some()
+            || self.contrast.is_some()
+            || self.saturation.is_some()
+            || self.hue.is_some()
             || self.kodachrome.is_some()
             || self.technicolor.is_some()
             || self.vintage.is_some()
             || self.polaroid.is_some()
             || self.sepia.is_some()
             || self.monochrome.is_some()
+            || self.greyscale.is_some()
+            || self.tint.is_some()
+            || self.tint_opacity.is_some()
+            || self.negate.is_some()
+            || self.autolevel.is_some()
+            || self.pixelate.is_some()
+            || self.vignette.is_some()
+            || self.posterize.is_some()
+            || self.threshold.is_some()
             || self.width.is_some()
             || self.height.is_some()
             || self.device_pixel_ratio.is_some()
             || self.rotate.is_some()
+            || self.flip.is_some()
+            || self.flop.is_some()
+            || self.page.is_some()
+            || self.frame.is_some()
+            || self.dpi.is_some()
+            || self.density.is_some()
+            || self.profile.is_some()
+            || self.strip.is_some()
             || self.fit.is_some()
+            || self.gravity.is_some()
+            || self.crop.is_some()
+            || self.kernel.is_some()
+            || self.watermark.is_some()
+            || self.watermark_gravity.is_some()
+            || self.watermark_opacity.is_some()
+            || self.watermark_scale.is_some()
+            || self.overlay.is_some()
+            || self.overlay_x.is_some()
+            || self.overlay_y.is_some()
+            || self.overlay_blend.is_some()
+            || self.text.is_some()
+            || self.text_colour.is_some()
+            || self.text_size.is_some()
+            || self.text_gravity.is_some()
+            || self.border.is_some()
+            || self.border_colour.is_some()
+            || self.radius.is_some()
             || self.format.is_some()
             || self.lossless.is_some()
+            || self.progressive.is_some()
+            || self.effort.is_some()
+            || self.encoder.is_some()
+            || self.bitdepth.is_some()
+            || self.meta.is_some()
+            || self.blurhash_x.is_some()
+            || self.blurhash_y.is_some()
     }
 
     /// Calculate the resize scale based on the image dimensions and the specified width and height.
@@ -221,6 +696,122 @@ impl ImageOptions {
         }
     }
 
+    /// The exact output dimensions for `fit=pad`, including the device pixel ratio.
+    pub fn pad_dimensions(&self) -> Option<(i32, i32)> {
+        if self.fit != Some(Fit::Pad) {
+            return None;
+        }
+        let dpr = self.device_pixel_ratio.unwrap_or(1.0);
+        Some((
+            scale_dimension(self.width?, dpr),
+            scale_dimension(self.height?, dpr),
+        ))
+    }
+
+    /// The requested output dimensions including the device pixel ratio, with a
+    /// missing side derived from the aspect ratio.
+    pub fn requested_dimensions(&self) -> (Option<i64>, Option<i64>) {
+        let (width, height) = match (self.width, self.height, &self.aspect_ratio) {
+            (Some(width), None, Some(ar)) => (Some(width), Some(width / ar.clone())),
+            (None, Some(height), Some(ar)) => (Some(height * ar.clone()), Some(height)),
+            (width, height, _) => (width, height),
+        };
+        let dpr = self.device_pixel_ratio.unwrap_or(1.0);
+        (
+            width.map(|width| i64::from(scale_dimension(width, dpr))),
+            height.map(|height| i64::from(scale_dimension(height, dpr))),
+        )
+    }
+
+    /// The requested output dimensions, with a side that's still missing derived
+    /// from the aspect ratio of the source.
+    pub fn output_dimensions(&self, source: (i32, i32)) -> (Option<i64>, Option<i64>) {
+        let source_width = i64::from(source.0.max(1));
+        let source_height = i64::from(source.1.max(1));
+        match self.requested_dimensions() {
+            (Some(width), None) => (
+                Some(width),
+                Some((width * source_height / source_width).max(1)),
+            ),
+            (None, Some(height)) => (
+                Some((height * source_width / source_height).max(1)),
+                Some(height),
+            ),
+            dimensions => dimensions,
+        }
+    }
+
+    /// Whether to sharpen, either from `sharpen` or the unsharp mask parameters.
+    /// `sharpen=0` turns sharpening off even when the others are set.
+    pub fn sharpens(&self) -> bool {
+        match self.sharpen {
+            Some(sharpen) => sharpen.0 > 0,
+            None => self.sharpen_radius.is_some() || self.sharpen_amount.is_some(),
+        }
+    }
+
+    /// Limit the device pixel ratio to `max`, treating values below 1 as 1.
+    pub fn clamp_device_pixel_ratio(&mut self, max: f64) {
+        if let Some(dpr) = self.device_pixel_ratio {
+            self.device_pixel_ratio = Some(dpr.clamp(1.0, max.max(1.0)));
+        }
+    }
+
+    /// Whether animation frames can be kept, which requires an animated output
+    /// format and no transformations that treat the frames as a single image.
+    pub fn preserves_animation(&self) -> bool {
+        let gravity_crop = self.fit == Some(Fit::Crop)
+            && self.crop.and_then(Crop::interesting).is_none()
+            && self.gravity.unwrap_or_default() != Gravity::Centre;
+        self.output_format().supports_animation()
+            && !gravity_crop
+            && self.crop.and_then(Crop::rect).is_none()
+            && self.fit != Some(Fit::Pad)
+            && self.fit != Some(Fit::Fill)
+            && self.frame.is_none()
+            && self.rotate.is_none()
+            && self.flip.is_none()
+            && self.flop.is_none()
+            && self.trim.is_none()
+            && self.watermark.is_none()
+            && self.overlay.is_none()
+            && self.text.is_none()
+            && self.border.is_none()
+            && self.radius.is_none()
+            && self.vignette.is_none()
+            && self.pixelate.is_none()
+    }
+
+    /// The ICC profile images are converted to and embedded in the output
+    pub fn output_profile(&self) -> &'static str {
+        match self.profile {
+            Some(Profile::Keep) => "p3",
+            Some(Profile::Srgb) | None => "sRGB",
+        }
+    }
+
+    /// The metadata to keep in the output. The colour profile is always kept so
+    /// colours render correctly, and orientation is already applied by `autorot`.
+    pub fn keep_metadata(&self) -> ops::ForeignKeep {
+        if self.strip.unwrap_or(true) {
+            ops::ForeignKeep::Icc
+        } else {
+            ops::ForeignKeep::All
+        }
+    }
+
+    /// The output format, defaulting to one that can represent rounded corners when requested.
+    pub fn output_format(&self) -> ImageFormat {
+        if self.meta == Some(true) {
+            return ImageFormat::Meta;
+        }
+        match self.format {
+            Some(format) => format,
+            None if self.radius.is_some() => ImageFormat::Png,
+            None => ImageFormat::Jpeg,
+        }
+    }
+
     pub fn query_str(&self) -> String {
         let mut params: BTreeMap<String, String> = BTreeMap::new();
 
@@ -234,6 +825,12 @@ impl ImageOptions {
         if let Some(ar) = &self.aspect_ratio {
             params.insert("ar".into(), ar.to_string());
         }
+        if let Some(expires) = self.expires {
+            params.insert("exp".into(), expires.to_string());
+        }
+        if let Some(preset) = &self.preset {
+            params.insert("preset".into(), preset.to_string());
+        }
         if let Some(download) = &self.download {
             params.insert("download".into(), download.to_string());
         }
@@ -243,12 +840,36 @@ impl ImageOptions {
         if let Some(trim_colour) = &self.trim_colour {
             params.insert("trim-colour".into(), trim_colour.into());
         }
+        if let Some(threshold) = self.trim_threshold {
+            params.insert("trim-threshold".into(), threshold.to_string());
+        }
+        if let Some(lineart) = self.trim_lineart {
+            params.insert("trim-lineart".into(), lineart.to_string());
+        }
         if let Some(sharpen) = &self.sharpen {
             params.insert("sharpen".into(), sharpen.0.to_string());
         }
+        if let Some(radius) = self.sharpen_radius {
+            params.insert("sharpen-radius".into(), radius.to_string());
+        }
+        if let Some(amount) = self.sharpen_amount {
+            params.insert("sharpen-amount".into(), amount.to_string());
+        }
         if let Some(blur) = &self.blur {
             params.insert("blur".into(), blur.0.to_string());
         }
+        if let Some(brightness) = &self.brightness {
+            params.insert("brightness".into(), brightness.0.to_string());
+        }
+        if let Some(contrast) = &self.contrast {
+            params.insert("contrast".into(), contrast.0.to_string());
+        }
+        if let Some(saturation) = &self.saturation {
+            params.insert("saturation".into(), saturation.0.to_string());
+        }
+        if let Some(hue) = self.hue {
+            params.insert("hue".into(), hue.to_string());
+        }
         if let Some(kodachrome) = &self.kodachrome {
             params.insert("kodachrome".into(), kodachrome.0.to_string());
         }
@@ -267,6 +888,33 @@ impl ImageOptions {
         if let Some(monochrome) = &self.monochrome {
             params.insert("monochrome".into(), monochrome.0.to_string());
         }
+        if let Some(greyscale) = self.greyscale {
+            params.insert("greyscale".into(), greyscale.to_string());
+        }
+        if let Some(tint) = &self.tint {
+            params.insert("tint".into(), tint.into());
+        }
+        if let Some(tint_opacity) = &self.tint_opacity {
+            params.insert("tint-opacity".into(), tint_opacity.0.to_string());
+        }
+        if let Some(negate) = self.negate {
+            params.insert("negate".into(), negate.to_string());
+        }
+        if let Some(autolevel) = self.autolevel {
+            params.insert("autolevel".into(), autolevel.to_string());
+        }
+        if let Some(pixelate) = self.pixelate {
+            params.insert("pixelate".into(), pixelate.to_string());
+        }
+        if let Some(vignette) = &self.vignette {
+            params.insert("vignette".into(), vignette.0.to_string());
+        }
+        if let Some(posterize) = self.posterize {
+            params.insert("posterize".into(), posterize.to_string());
+        }
+        if let Some(threshold) = &self.threshold {
+            params.insert("threshold".into(), threshold.0.to_string());
+        }
         if let Some(width) = self.width {
             params.insert("width".into(), width.to_string());
         }
@@ -279,15 +927,114 @@ impl ImageOptions {
         if let Some(rot) = &self.rotate {
             params.insert("rot".into(), rot.0.to_string());
         }
+        if let Some(flip) = self.flip {
+            params.insert("flip".into(), flip.to_string());
+        }
+        if let Some(flop) = self.flop {
+            params.insert("flop".into(), flop.to_string());
+        }
+        if let Some(page) = self.page {
+            params.insert("page".into(), page.to_string());
+        }
+        if let Some(frame) = self.frame {
+            params.insert("frame".into(), frame.to_string());
+        }
+        if let Some(dpi) = self.dpi {
+            params.insert("dpi".into(), dpi.to_string());
+        }
+        if let Some(density) = self.density {
+            params.insert("density".into(), density.to_string());
+        }
+        if let Some(profile) = &self.profile {
+            params.insert("profile".into(), profile.to_string());
+        }
+        if let Some(strip) = self.strip {
+            params.insert("strip".into(), strip.to_string());
+        }
         if let Some(fit) = &self.fit {
             params.insert("fit".into(), fit.to_string().to_lowercase());
         }
+        if let Some(gravity) = &self.gravity {
+            params.insert("gravity".into(), gravity.to_string());
+        }
+        if let Some(crop) = &self.crop {
+            params.insert("crop".into(), crop.to_string());
+        }
+        if let Some(kernel) = self.kernel {
+            params.insert("kernel".into(), kernel.to_string());
+        }
+        if let Some(watermark) = &self.watermark {
+            params.insert("wm".into(), watermark.to_string());
+        }
+        if let Some(watermark_gravity) = &self.watermark_gravity {
+            params.insert("wm-gravity".into(), watermark_gravity.to_string());
+        }
+        if let Some(watermark_opacity) = &self.watermark_opacity {
+            params.insert("wm-opacity".into(), watermark_opacity.0.to_string());
+        }
+        if let Some(watermark_scale) = &self.watermark_scale {
+            params.insert("wm-scale".into(), watermark_scale.0.to_string());
+        }
+        if let Some(overlay) = &self.overlay {
+            params.insert("overlay".into(), overlay.to_string());
+        }
+        if let Some(overlay_x) = self.overlay_x {
+            params.insert("overlay-x".into(), overlay_x.to_string());
+        }
+        if let Some(overlay_y) = self.overlay_y {
+            params.insert("overlay-y".into(), overlay_y.to_string());
+        }
+        if let Some(overlay_blend) = self.overlay_blend {
+            params.insert("overlay-blend".into(), overlay_blend.to_string());
+        }
+        if let Some(text) = &self.text {
+            params.insert("txt".into(), text.to_string());
+        }
+        if let Some(text_colour) = &self.text_colour {
+            params.insert("txt-colour".into(), text_colour.into());
+        }
+        if let Some(text_size) = self.text_size {
+            params.insert("txt-size".into(), text_size.to_string());
+        }
+        if let Some(text_gravity) = &self.text_gravity {
+            params.insert("txt-gravity".into(), text_gravity.to_string());
+        }
+        if let Some(border) = self.border {
+            params.insert("border".into(), border.to_string());
+        }
+        if let Some(border_colour) = &self.border_colour {
+            params.insert("border-colour".into(), border_colour.into());
+        }
+        if let Some(radius) = &self.radius {
+            params.insert("radius".into(), radius.to_string());
+        }
         if let Some(fmt) = &self.format {
             params.insert("format".into(), fmt.to_string());
         }
         if let Some(lossless) = self.lossless {
             params.insert("lossless".into(), lossless.to_string());
         }
+        if let Some(progressive) = self.progressive {
+            params.insert("progressive".into(), progressive.to_string());
+        }
+        if let Some(effort) = self.effort {
+            params.insert("effort".into(), effort.to_string());
+        }
+        if let Some(encoder) = self.encoder {
+            params.insert("encoder".into(), encoder.to_string());
+        }
+        if let Some(bitdepth) = self.bitdepth {
+            params.insert("bitdepth".into(), bitdepth.to_string());
+        }
+        if let Some(meta) = self.meta {
+            params.insert("meta".into(), meta.to_string());
+        }
+        if let Some(blurhash_x) = self.blurhash_x {
+            params.insert("bh-x".into(), blurhash_x.to_string());
+        }
+        if let Some(blurhash_y) = self.blurhash_y {
+            params.insert("bh-y".into(), blurhash_y.to_string());
+        }
 
         // Create the query string
         params
@@ -297,48 +1044,369 @@ impl ImageOptions {
             .join("&")
     }
 
-    pub fn sign(&self, secret: &str) -> std::string::String {
-        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
-        hex::encode(hmac::sign(&key, self.query_str().as_bytes()).as_ref())
+    /// The message covered by the signature: the request path, so a signature can't
+    /// be reused for another image, followed by the canonical query string.
+    fn signed_message(&self, path: &str) -> String {
+        format!("{}?{}", path, self.query_str())
+    }
+
+    pub fn sign(&self, secret: &str, path: &str, algorithm: SigningAlgorithm) -> String {
+        let key = hmac::Key::new(algorithm.into(), secret.as_bytes());
+        hex::encode(hmac::sign(&key, self.signed_message(path).as_bytes()).as_ref())
+    }
+
+    pub fn verify_signature(
+        &self,
+        signing_secret: &str,
+        path: &str,
+        algorithm: SigningAlgorithm,
+    ) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        self.verify_signature_at(signing_secret, path, algorithm, now)
+    }
+
+    /// Verify the signature as of `now`, rejecting URLs whose `exp` has passed.
+    fn verify_signature_at(
+        &self,
+        signing_secret: &str,
+        path: &str,
+        algorithm: SigningAlgorithm,
+        now: i64,
+    ) -> bool {
+        if self.expires.is_some_and(|expires| expires < now) {
+            return false;
+        }
+        if let Some(ref sig_hex) = self.signature
+            && let Ok(sig_bytes) = hex::decode(sig_hex)
+        {
+            let key = hmac::Key::new(algorithm.into(), signing_secret.as_bytes());
+            let message = self.signed_message(path);
+            return ring::hmac::verify(&key, message.as_bytes(), &sig_bytes).is_ok();
+        }
+        false
+    }
+}
+
+/// Combine a query string with default parameters, keeping the query's own value
+/// for any parameter set in both.
+pub fn merge_query(query: &str, defaults: &BTreeMap<String, String>) -> String {
+    let explicit: Vec<(String, String)> = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    let mut merged = url::form_urlencoded::Serializer::new(String::new());
+    for (name, value) in defaults {
+        if !explicit.iter().any(|(explicit, _)| explicit == name) {
+            merged.append_pair(name, value);
+        }
+    }
+    merged.extend_pairs(&explicit);
+    merged.finish()
+}
+
+/// Parse a query string on top of a preset's parameters, with the query's own
+/// values taking precedence.
+pub fn with_preset(query: &str, preset: &BTreeMap<String, String>) -> Result<ImageOptions, String> {
+    let uri: Uri = format!("/?{}", merge_query(query, preset))
+        .parse()
+        .map_err(|err: axum::http::uri::InvalidUri| err.to_string())?;
+    Query::<ImageOptions>::try_from_uri(&uri)
+        .map(|query| query.0)
+        .map_err(|rejection| rejection.body_text())
+}
+
+#[derive(Debug, Serialize, Clone, Copy, Deserialize, PartialEq)]
+pub struct Percentage(pub i32);
+
+impl From<&Percentage> for f64 {
+    fn from(val: &Percentage) -> Self {
+        f64::from(val.0)
+    }
+}
+
+/// Parse a percentage, rejecting values outside of `range`
+fn deserialize_percentage_in<'de, D>(
+    deserializer: D,
+    range: RangeInclusive<i32>,
+) -> Result<Option<Percentage>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    let percentage = value
+        .parse::<i32>()
+        .map_err(|err| serde::de::Error::custom(format!("failed to parse percentage: {}", err)))?;
+    if !range.contains(&percentage) {
+        return Err(serde::de::Error::custom(format!(
+            "percentage must be between {} and {}",
+            range.start(),
+            range.end()
+        )));
+    }
+    Ok(Some(Percentage(percentage)))
+}
+
+fn deserialize_percentage<'de, D>(deserializer: D) -> Result<Option<Percentage>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_percentage_in(deserializer, 1..=100)
+}
+
+/// A percentage where 0 disables the filter
+fn deserialize_strength<'de, D>(deserializer: D) -> Result<Option<Percentage>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_percentage_in(deserializer, 0..=100)
+}
+
+fn deserialize_signed_percentage<'de, D>(deserializer: D) -> Result<Option<Percentage>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_percentage_in(deserializer, -100..=100)
+}
+
+fn deserialize_saturation<'de, D>(deserializer: D) -> Result<Option<Percentage>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = i32::deserialize(deserializer);
+    match result {
+        Ok(value) if (0..=300).contains(&value) => Ok(Some(Percentage(value))),
+        Ok(_) => Err(serde::de::Error::custom(
+            "saturation must be between 0 and 300",
+        )),
+        Err(err) => Err(err),
+    }
+}
+
+fn deserialize_hue<'de, D>(deserializer: D) -> Result<Option<i32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = i32::deserialize(deserializer);
+    match result {
+        Ok(value) if (0..=360).contains(&value) => Ok(Some(value)),
+        Ok(_) => Err(serde::de::Error::custom("hue must be between 0 and 360")),
+        Err(err) => Err(err),
+    }
+}
+
+const MAX_TEXT_LENGTH: usize = 256;
+
+fn deserialize_text<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = String::deserialize(deserializer);
+    match result {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) if value.chars().count() > MAX_TEXT_LENGTH => Err(serde::de::Error::custom(
+            format!("text must be at most {MAX_TEXT_LENGTH} characters"),
+        )),
+        Ok(value) => Ok(Some(value)),
+        Err(err) => Err(err),
+    }
+}
+
+fn deserialize_text_size<'de, D>(deserializer: D) -> Result<Option<i32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = i32::deserialize(deserializer);
+    match result {
+        Ok(value) if (1..=512).contains(&value) => Ok(Some(value)),
+        Ok(_) => Err(serde::de::Error::custom(
+            "text size must be between 1 and 512",
+        )),
+        Err(err) => Err(err),
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, Deserialize, PartialEq)]
+pub enum Radius {
+    Pixels(i32),
+    Percent(i32),
+}
+
+impl Radius {
+    /// Resolve the radius in pixels for an image of the given dimensions
+    pub fn pixels(self, width: i32, height: i32) -> i32 {
+        let shorter = width.min(height);
+        match self {
+            Radius::Pixels(radius) => radius.min(shorter / 2),
+            Radius::Percent(percent) => shorter * percent / 100,
+        }
+    }
+}
+
+impl Display for Radius {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Radius::Pixels(radius) => write!(f, "{radius}"),
+            Radius::Percent(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
+
+fn deserialize_radius<'de, D>(deserializer: D) -> Result<Option<Radius>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = String::deserialize(deserializer);
+    match result {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => {
+            if let Some(percent) = value.strip_suffix('%') {
+                return match percent.parse::<i32>() {
+                    Ok(percent) if (1..=50).contains(&percent) => {
+                        Ok(Some(Radius::Percent(percent)))
+                    }
+                    _ => Err(serde::de::Error::custom(
+                        "radius percentage must be between 1 and 50",
+                    )),
+                };
+            }
+            match value.parse::<i32>() {
+                Ok(radius) if radius > 0 => Ok(Some(Radius::Pixels(radius))),
+                _ => Err(serde::de::Error::custom("invalid radius")),
+            }
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn deserialize_dpi<'de, D>(deserializer: D) -> Result<Option<i32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = i32::deserialize(deserializer);
+    match result {
+        Ok(value) if (1..=1200).contains(&value) => Ok(Some(value)),
+        Ok(_) => Err(serde::de::Error::custom("dpi must be between 1 and 1200")),
+        Err(err) => Err(err),
+    }
+}
+
+fn deserialize_device_pixel_ratio<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = f64::deserialize(deserializer);
+    match result {
+        Ok(value) if value.is_finite() => Ok(Some(value)),
+        Ok(_) => Err(serde::de::Error::custom("dpr must be a finite number")),
+        Err(err) => Err(err),
+    }
+}
+
+fn deserialize_sharpen_radius<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = f64::deserialize(deserializer);
+    match result {
+        Ok(value) if value > 0.0 && value <= 10.0 => Ok(Some(value)),
+        Ok(_) => Err(serde::de::Error::custom(
+            "sharpen-radius must be greater than 0 and at most 10",
+        )),
+        Err(err) => Err(err),
+    }
+}
+
+fn deserialize_sharpen_amount<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = f64::deserialize(deserializer);
+    match result {
+        Ok(value) if (0.0..=10.0).contains(&value) => Ok(Some(value)),
+        Ok(_) => Err(serde::de::Error::custom(
+            "sharpen-amount must be between 0 and 10",
+        )),
+        Err(err) => Err(err),
+    }
+}
+
+fn deserialize_trim_threshold<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = f64::deserialize(deserializer);
+    match result {
+        Ok(value) if (0.0..=255.0).contains(&value) => Ok(Some(value)),
+        Ok(_) => Err(serde::de::Error::custom(
+            "trim-threshold must be between 0 and 255",
+        )),
+        Err(err) => Err(err),
+    }
+}
+
+fn deserialize_effort<'de, D>(deserializer: D) -> Result<Option<i32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = i32::deserialize(deserializer);
+    match result {
+        Ok(value) if (0..=9).contains(&value) => Ok(Some(value)),
+        Ok(_) => Err(serde::de::Error::custom("effort must be between 0 and 9")),
+        Err(err) => Err(err),
     }
+}
 
-    pub fn verify_signature(&self, signing_secret: &str) -> bool {
-        if let Some(ref sig_hex) = self.signature
-            && let Ok(sig_bytes) = hex::decode(sig_hex)
-        {
-            let key = hmac::Key::new(hmac::HMAC_SHA256, signing_secret.as_bytes());
-            return ring::hmac::verify(&key, self.query_str().as_bytes(), &sig_bytes).is_ok();
-        }
-        false
+fn deserialize_posterize<'de, D>(deserializer: D) -> Result<Option<i32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = i32::deserialize(deserializer);
+    match result {
+        Ok(value) if (2..=256).contains(&value) => Ok(Some(value)),
+        Ok(_) => Err(serde::de::Error::custom(
+            "posterize must be between 2 and 256",
+        )),
+        Err(err) => Err(err),
     }
 }
 
-#[derive(Debug, Serialize, Clone, Copy, Deserialize, PartialEq)]
-pub struct Percentage(pub i32);
+/// A boolean that also accepts `1` and `0`
+fn deserialize_flag<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = String::deserialize(deserializer);
+    match result {
+        Ok(value) => match value.as_str() {
+            "1" | "true" => Ok(Some(true)),
+            "0" | "false" => Ok(Some(false)),
+            _ => Err(serde::de::Error::custom("expected true, false, 1 or 0")),
+        },
+        Err(err) => Err(err),
+    }
+}
 
-impl From<&Percentage> for f64 {
-    fn from(val: &Percentage) -> Self {
-        f64::from(val.0)
+fn deserialize_blurhash_components<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let result = u32::deserialize(deserializer);
+    match result {
+        Ok(value) if (1..=9).contains(&value) => Ok(Some(value)),
+        Ok(_) => Err(serde::de::Error::custom(
+            "blurhash components must be between 1 and 9",
+        )),
+        Err(err) => Err(err),
     }
 }
 
-fn deserialize_percentage<'de, D>(deserializer: D) -> Result<Option<Percentage>, D::Error>
+fn deserialize_bitdepth<'de, D>(deserializer: D) -> Result<Option<i32>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let result = String::deserialize(deserializer);
+    let result = i32::deserialize(deserializer);
     match result {
-        Ok(value) => {
-            let percentage = value.parse::<i32>().map_err(|err| {
-                serde::de::Error::custom(format!("failed to parse percenpercentagetage: {}", err))
-            })?;
-            if !(1..=100).contains(&percentage) {
-                return Err(serde::de::Error::custom(
-                    "percentage must be between 1 and 100",
-                ));
-            }
-            Ok(Some(Percentage(percentage)))
-        }
+        Ok(value) if matches!(value, 8 | 10 | 12) => Ok(Some(value)),
+        Ok(_) => Err(serde::de::Error::custom("bitdepth must be 8, 10 or 12")),
         Err(err) => Err(err),
     }
 }
@@ -423,7 +1491,9 @@ where
     }
 }
 
-#[derive(Debug, Display, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[derive(
+    Debug, Display, EnumString, IntoStaticStr, PartialEq, Serialize, Deserialize, Clone, Copy,
+)]
 #[serde(rename_all = "lowercase")]
 pub enum ImageFormat {
     #[strum(serialize = "avif")]
@@ -434,9 +1504,46 @@ pub enum ImageFormat {
     Webp,
     #[strum(serialize = "png")]
     Png,
+    #[strum(serialize = "gif")]
+    Gif,
+    /// A BlurHash placeholder string rather than an image
+    #[strum(serialize = "blurhash")]
+    Blurhash,
+    /// The average colour of the image as JSON rather than an image
+    #[serde(alias = "color")]
+    #[strum(to_string = "colour", serialize = "color")]
+    Colour,
+    /// The source image metadata as JSON, requested with `meta` rather than `fm`
+    #[serde(skip)]
+    #[strum(serialize = "meta")]
+    Meta,
 }
 
 impl ImageFormat {
+    /// The encoder quality used when neither the request nor the config sets one
+    #[must_use]
+    pub fn default_quality(self) -> i32 {
+        match self {
+            ImageFormat::Avif => 75,
+            _ => 80,
+        }
+    }
+
+    /// Whether the format can store an alpha channel
+    #[must_use]
+    pub fn supports_alpha(self) -> bool {
+        !matches!(
+            self,
+            ImageFormat::Jpeg | ImageFormat::Blurhash | ImageFormat::Colour | ImageFormat::Meta
+        )
+    }
+
+    /// Whether the format can store multiple animation frames
+    #[must_use]
+    pub fn supports_animation(self) -> bool {
+        matches!(self, ImageFormat::Webp | ImageFormat::Gif)
+    }
+
     #[must_use]
     pub fn content_type(self) -> &'static str {
         match self {
@@ -444,6 +1551,9 @@ impl ImageFormat {
             ImageFormat::Jpeg => "image/jpeg",
             ImageFormat::Webp => "image/webp",
             ImageFormat::Png => "image/png",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Blurhash => "text/plain",
+            ImageFormat::Colour | ImageFormat::Meta => "application/json",
         }
     }
 }
@@ -503,18 +1613,31 @@ impl Mul<AspectRatio> for i32 {
     }
 }
 
+/// Scale a dimension by the device pixel ratio, rounding like `AspectRatio`.
+#[allow(clippy::cast_possible_truncation)]
+fn scale_dimension(value: i32, dpr: f64) -> i32 {
+    (f64::from(value) * dpr).round() as i32
+}
+
 impl From<&mut ImageOptions> for ops::HeifsaveBufferOptions {
     fn from(options: &mut ImageOptions) -> ops::HeifsaveBufferOptions {
         let mut opts = ops::HeifsaveBufferOptions {
-            q: options.quality.unwrap_or(75),
+            q: options
+                .quality
+                .unwrap_or(ImageFormat::Avif.default_quality()),
             lossless: options.lossless.unwrap_or(false),
             compression: ops::ForeignHeifCompression::Hevc,
-            effort: 4,
+            effort: options.effort.unwrap_or(4),
+            encoder: options
+                .encoder
+                .map_or(ops::ForeignHeifEncoder::Auto, Into::into),
+            profile: options.output_profile().to_string(),
+            keep: options.keep_metadata(),
             ..Default::default()
         };
         if let Some(ImageFormat::Avif) = options.format {
             opts.compression = ops::ForeignHeifCompression::Av1;
-            opts.bitdepth = 8;
+            opts.bitdepth = options.bitdepth.unwrap_or(8);
         }
         opts
     }
@@ -523,8 +1646,12 @@ impl From<&mut ImageOptions> for ops::HeifsaveBufferOptions {
 impl From<&mut ImageOptions> for ops::WebpsaveBufferOptions {
     fn from(options: &mut ImageOptions) -> ops::WebpsaveBufferOptions {
         ops::WebpsaveBufferOptions {
-            q: options.quality.unwrap_or(80),
+            q: options
+                .quality
+                .unwrap_or(ImageFormat::Webp.default_quality()),
             lossless: options.lossless.unwrap_or(false),
+            profile: options.output_profile().to_string(),
+            keep: options.keep_metadata(),
             ..Default::default()
         }
     }
@@ -533,10 +1660,15 @@ impl From<&mut ImageOptions> for ops::WebpsaveBufferOptions {
 impl From<&mut ImageOptions> for ops::JpegsaveBufferOptions {
     fn from(options: &mut ImageOptions) -> ops::JpegsaveBufferOptions {
         ops::JpegsaveBufferOptions {
-            q: options.quality.unwrap_or(80),
+            q: options
+                .quality
+                .unwrap_or(ImageFormat::Jpeg.default_quality()),
             optimize_coding: false,
-            // Setting interlace to true slows down the encoding process significantly
-            interlace: false,
+            // Setting interlace to true slows down the encoding process significantly,
+            // so progressive output is opt-in
+            interlace: options.progressive.unwrap_or(false),
+            profile: options.output_profile().to_string(),
+            keep: options.keep_metadata(),
             ..Default::default()
         }
     }
@@ -545,14 +1677,31 @@ impl From<&mut ImageOptions> for ops::JpegsaveBufferOptions {
 impl From<&mut ImageOptions> for ops::PngsaveBufferOptions {
     fn from(options: &mut ImageOptions) -> ops::PngsaveBufferOptions {
         ops::PngsaveBufferOptions {
-            q: options.quality.unwrap_or(80),
+            q: options
+                .quality
+                .unwrap_or(ImageFormat::Png.default_quality()),
             compression: 6,
             interlace: true,
+            profile: options.output_profile().to_string(),
+            keep: options.keep_metadata(),
             ..Default::default()
         }
     }
 }
 
+#[derive(Display, PartialEq, Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// Convert to sRGB, clipping colours outside of its gamut.
+    #[strum(serialize = "srgb")]
+    Srgb,
+
+    /// Keep wide-gamut colours by converting to Display P3 rather than sRGB,
+    /// when the embedded profile reaches beyond sRGB.
+    #[strum(serialize = "keep")]
+    Keep,
+}
+
 #[derive(Display, PartialEq, Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum Fit {
@@ -564,6 +1713,282 @@ pub enum Fit {
 
     /// Fits within bounds without cropping or distortion but won't upscale smaller images.
     Max,
+
+    /// Fits within bounds like `Clip`, then pads with the background colour to the exact dimensions.
+    Pad,
+
+    /// Stretches to the exact dimensions, distorting the aspect ratio if needed.
+    Fill,
+}
+
+#[derive(Display, PartialEq, Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum BlendMode {
+    /// Place the overlay on top, respecting its alpha.
+    Over,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    #[serde(alias = "color-dodge")]
+    ColourDodge,
+    #[serde(alias = "color-burn")]
+    ColourBurn,
+    HardLight,
+    SoftLight,
+    Difference,
+    Exclusion,
+}
+
+impl BlendMode {
+    pub fn to_vips(self) -> ops::BlendMode {
+        match self {
+            BlendMode::Over => ops::BlendMode::Over,
+            BlendMode::Multiply => ops::BlendMode::Multiply,
+            BlendMode::Screen => ops::BlendMode::Screen,
+            BlendMode::Overlay => ops::BlendMode::Overlay,
+            BlendMode::Darken => ops::BlendMode::Darken,
+            BlendMode::Lighten => ops::BlendMode::Lighten,
+            BlendMode::ColourDodge => ops::BlendMode::ColourDodge,
+            BlendMode::ColourBurn => ops::BlendMode::ColourBurn,
+            BlendMode::HardLight => ops::BlendMode::HardLight,
+            BlendMode::SoftLight => ops::BlendMode::SoftLight,
+            BlendMode::Difference => ops::BlendMode::Difference,
+            BlendMode::Exclusion => ops::BlendMode::Exclusion,
+        }
+    }
+}
+
+#[derive(Display, PartialEq, Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Kernel {
+    /// Nearest-neighbour, for hard pixel edges.
+    Nearest,
+
+    /// Bilinear interpolation.
+    Linear,
+
+    /// Bicubic interpolation.
+    Cubic,
+
+    /// Two-lobe Lanczos.
+    Lanczos2,
+
+    /// Three-lobe Lanczos, the libvips default.
+    Lanczos3,
+}
+
+impl Kernel {
+    pub fn to_vips(self) -> ops::Kernel {
+        match self {
+            Kernel::Nearest => ops::Kernel::Nearest,
+            Kernel::Linear => ops::Kernel::Linear,
+            Kernel::Cubic => ops::Kernel::Cubic,
+            Kernel::Lanczos2 => ops::Kernel::Lanczos2,
+            Kernel::Lanczos3 => ops::Kernel::Lanczos3,
+        }
+    }
+}
+
+#[derive(Display, PartialEq, Debug, Deserialize, Serialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Gravity {
+    /// Anchor to the centre of the image.
+    #[default]
+    #[serde(rename = "center", alias = "centre")]
+    #[strum(serialize = "center")]
+    Centre,
+
+    /// Anchor to the top edge.
+    #[strum(serialize = "north")]
+    North,
+
+    /// Anchor to the bottom edge.
+    #[strum(serialize = "south")]
+    South,
+
+    /// Anchor to the right edge.
+    #[strum(serialize = "east")]
+    East,
+
+    /// Anchor to the left edge.
+    #[strum(serialize = "west")]
+    West,
+
+    /// Anchor to the top right corner.
+    #[strum(serialize = "northeast")]
+    NorthEast,
+
+    /// Anchor to the top left corner.
+    #[strum(serialize = "northwest")]
+    NorthWest,
+
+    /// Anchor to the bottom right corner.
+    #[strum(serialize = "southeast")]
+    SouthEast,
+
+    /// Anchor to the bottom left corner.
+    #[strum(serialize = "southwest")]
+    SouthWest,
+}
+
+impl Gravity {
+    /// Calculate the offset of an `inner` area placed within an `outer` area.
+    pub fn offsets(self, outer: (i32, i32), inner: (i32, i32)) -> (i32, i32) {
+        let (outer_width, outer_height) = outer;
+        let (inner_width, inner_height) = inner;
+        let centre_x = (outer_width - inner_width) / 2;
+        let centre_y = (outer_height - inner_height) / 2;
+        let right = outer_width - inner_width;
+        let bottom = outer_height - inner_height;
+        match self {
+            Gravity::Centre => (centre_x, centre_y),
+            Gravity::North => (centre_x, 0),
+            Gravity::South => (centre_x, bottom),
+            Gravity::East => (right, centre_y),
+            Gravity::West => (0, centre_y),
+            Gravity::NorthEast => (right, 0),
+            Gravity::NorthWest => (0, 0),
+            Gravity::SouthEast => (right, bottom),
+            Gravity::SouthWest => (0, bottom),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Crop {
+    /// Crop towards the most interesting region, favouring skin tones and saturated colours.
+    Smart,
+
+    /// Crop towards the region with the highest entropy.
+    Entropy,
+
+    /// Extract an exact rectangle before resizing.
+    Rect(CropRect),
+}
+
+impl Crop {
+    /// The libvips strategy for content-aware crops
+    pub fn interesting(self) -> Option<ops::Interesting> {
+        match self {
+            Crop::Smart => Some(ops::Interesting::Attention),
+            Crop::Entropy => Some(ops::Interesting::Entropy),
+            Crop::Rect(_) => None,
+        }
+    }
+
+    pub fn rect(self) -> Option<CropRect> {
+        match self {
+            Crop::Rect(rect) => Some(rect),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Crop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Crop::Smart => write!(f, "smart"),
+            Crop::Entropy => write!(f, "entropy"),
+            Crop::Rect(rect) => write!(f, "{},{},{},{}", rect.x, rect.y, rect.width, rect.height),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Serialize, Clone, Copy)]
+pub struct CropRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl CropRect {
+    /// Clamp the rectangle to the image bounds, or `None` if nothing is left.
+    pub fn clamp(self, image_width: i32, image_height: i32) -> Option<CropRect> {
+        let x = self.x.clamp(0, image_width);
+        let y = self.y.clamp(0, image_height);
+        let width = self.x.saturating_add(self.width).min(image_width) - x;
+        let height = self.y.saturating_add(self.height).min(image_height) - y;
+        (width > 0 && height > 0).then_some(CropRect {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+fn deserialize_crop<'de, D>(deserializer: D) -> Result<Option<Crop>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    match value.as_str() {
+        "smart" => return Ok(Some(Crop::Smart)),
+        "entropy" => return Ok(Some(Crop::Entropy)),
+        _ => {}
+    }
+    deserialize_crop_rect(&value).map_err(serde::de::Error::custom)
+}
+
+/// Parse `x,y,w,h`, ignoring rectangles with no area
+fn deserialize_crop_rect(value: &str) -> Result<Option<Crop>, String> {
+    let error = || format!("crop must be smart, entropy or x,y,w,h, got `{value}`");
+    let parts = value
+        .split(',')
+        .map(|part| part.trim().parse::<i32>().map_err(|_| error()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let [x, y, width, height] = parts[..] else {
+        return Err(error());
+    };
+    if width < 0 || height < 0 {
+        return Err("crop width and height must not be negative".into());
+    }
+    if width == 0 || height == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Crop::Rect(CropRect {
+        x,
+        y,
+        width,
+        height,
+    })))
+}
+
+#[derive(Display, PartialEq, Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoder {
+    /// Let libheif pick the best available AV1 encoder.
+    #[strum(serialize = "auto")]
+    Auto,
+
+    /// The reference AV1 encoder.
+    #[strum(serialize = "aom")]
+    Aom,
+
+    /// The rav1e encoder.
+    #[strum(serialize = "rav1e")]
+    Rav1e,
+
+    /// The SVT-AV1 encoder, faster at lower efforts.
+    #[strum(serialize = "svt")]
+    Svt,
+}
+
+impl From<Encoder> for ops::ForeignHeifEncoder {
+    fn from(encoder: Encoder) -> Self {
+        match encoder {
+            Encoder::Auto => ops::ForeignHeifEncoder::Auto,
+            Encoder::Aom => ops::ForeignHeifEncoder::Aom,
+            Encoder::Rav1e => ops::ForeignHeifEncoder::Rav1E,
+            Encoder::Svt => ops::ForeignHeifEncoder::Svt,
+        }
+    }
 }
 
 fn deserialize_aspect_ratio<'de, D>(deserializer: D) -> Result<Option<AspectRatio>, D::Error>
@@ -653,6 +2078,21 @@ fn calculate_clip_dimensions(
     }
 }
 
+fn calculate_fill_dimensions(
+    image_options: &ImageOptions,
+    image_width: i32,
+    image_height: i32,
+    aspect_ratio: Option<AspectRatio>,
+) -> (i32, i32) {
+    match (image_options.width, image_options.height) {
+        // Both dimensions are used exactly, ignoring the aspect ratio
+        (Some(width), Some(height)) => (width, height),
+
+        // A single dimension has nothing to stretch to, so resize like clip
+        _ => calculate_clip_dimensions(image_options, image_width, image_height, aspect_ratio),
+    }
+}
+
 #[allow(clippy::cast_possible_truncation)]
 fn calculate_max_dimensions(
     image_options: &ImageOptions,
@@ -699,11 +2139,17 @@ fn calculate_max_dimensions(
 }
 
 pub fn calculate_dimensions(image_options: &mut ImageOptions, image_width: i32, image_height: i32) {
-    let dpr = image_options.device_pixel_ratio.unwrap_or(1);
+    // Apply the Device Pixel Ratio (DPR) to the requested size up front, so each
+    // side is only scaled once and `fit=max` limits the size in physical pixels
+    let dpr = image_options.device_pixel_ratio.unwrap_or(1.0);
+    image_options.width = image_options.width.map(|width| scale_dimension(width, dpr));
+    image_options.height = image_options
+        .height
+        .map(|height| scale_dimension(height, dpr));
 
     let aspect_ratio = match image_options.aspect_ratio.clone() {
         Some(ar) => Some(ar),
-        None => Some(AspectRatio::new(image_width * dpr, image_height * dpr)),
+        None => Some(AspectRatio::new(image_width, image_height)),
     };
 
     // Determine the new dimensions based on the `fit` parameter
@@ -714,21 +2160,23 @@ pub fn calculate_dimensions(image_options: &mut ImageOptions, image_width: i32,
         Some(Fit::Max) => {
             calculate_max_dimensions(image_options, image_width, image_height, aspect_ratio)
         }
-        Some(Fit::Clip) | None => {
+        Some(Fit::Fill) => {
+            calculate_fill_dimensions(image_options, image_width, image_height, aspect_ratio)
+        }
+        Some(Fit::Clip | Fit::Pad) | None => {
             calculate_clip_dimensions(image_options, image_width, image_height, aspect_ratio)
         }
     };
 
-    // Apply the Device Pixel Ratio (DPR) scaling
-    image_options.width = Some(width * dpr);
-    image_options.height = Some(height * dpr);
+    image_options.width = Some(width);
+    image_options.height = Some(height);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use axum::{extract::Query, http::Uri};
     use rstest::rstest;
+    use std::str::FromStr;
 
     #[rstest]
     // No resizing
@@ -770,6 +2218,25 @@ mod tests {
     #[case::max_width_and_height_invalid("?w=0&h=0&fit=max", (600, 400), (600, 400))]
     #[case::max_width_and_height("?w=300&h=200&fit=max", (600, 400), (300, 200))]
     #[case::max_width_and_height("?w=100&h=100&fit=max", (600, 400), (100, 67))]
+    // Pad: resized like clip before padding
+    #[case::pad_width_only("?w=150&fit=pad", (600, 400), (150, 100))]
+    #[case::pad_width_and_height("?w=100&h=100&fit=pad", (600, 400), (100, 67))]
+    // Device pixel ratio is applied once to each side
+    #[case::dpr_crop_width_only("?w=150&fit=crop&dpr=2", (600, 400), (300, 200))]
+    #[case::dpr_crop_height_only("?h=100&fit=crop&dpr=2", (600, 400), (300, 200))]
+    #[case::dpr_crop_width_and_height("?w=100&h=100&fit=crop&dpr=2", (600, 400), (200, 200))]
+    #[case::dpr_crop_aspect_ratio("?w=100&ar=1:1&fit=crop&dpr=2", (600, 400), (200, 200))]
+    #[case::dpr_clip_width_only("?w=100&fit=clip&dpr=2", (600, 400), (200, 133))]
+    #[case::dpr_clip_width_and_height("?w=150&h=150&dpr=3", (600, 400), (450, 300))]
+    #[case::dpr_max_within("?w=150&fit=max&dpr=2", (600, 400), (300, 200))]
+    #[case::dpr_max_no_upscale("?w=400&fit=max&dpr=2", (600, 400), (600, 400))]
+    #[case::dpr_fractional_clip("?w=100&fit=clip&dpr=1.5", (600, 400), (150, 100))]
+    #[case::dpr_fractional_crop("?w=101&h=51&fit=crop&dpr=1.5", (600, 400), (152, 77))]
+    // Fill: stretched to both dimensions
+    #[case::fill_width_only("?w=150&fit=fill", (600, 400), (150, 100))]
+    #[case::fill_height_only("?h=200&fit=fill", (600, 400), (300, 200))]
+    #[case::fill_width_and_height("?w=100&h=100&fit=fill", (600, 400), (100, 100))]
+    #[case::fill_upscale("?w=1200&h=100&fit=fill", (600, 400), (1200, 100))]
     fn test_calculate_dimensions(
         #[case] query: &str,
         #[case] image_dimensions: (i32, i32),
@@ -787,13 +2254,284 @@ mod tests {
         );
     }
 
+    fn parse_query(query: &str) -> Option<ImageOptions> {
+        let url = String::from("https://google.com/image.jpg") + query;
+        let uri: Uri = url.parse().expect("failed to parse url");
+        Query::<ImageOptions>::try_from_uri(&uri)
+            .ok()
+            .map(|query| query.0)
+    }
+
+    #[rstest]
+    #[case::brightness("?bri=-50", true)]
+    #[case::contrast("?con=100", true)]
+    #[case::brightness_out_of_range("?bri=101", false)]
+    #[case::contrast_out_of_range("?con=-101", false)]
+    #[case::saturation("?sat=0", true)]
+    #[case::saturation_out_of_range("?sat=301", false)]
+    #[case::hue("?hue=360", true)]
+    #[case::hue_out_of_range("?hue=-1", false)]
+    #[case::effort("?effort=9", true)]
+    #[case::effort_out_of_range("?effort=10", false)]
+    #[case::blur_zero("?blur=0", true)]
+    #[case::blur_out_of_range("?blur=101", false)]
+    #[case::sharpen_zero("?sharpen=0", true)]
+    #[case::sharpen_negative("?sharpen=-1", false)]
+    #[case::sharpen_radius("?sharpen-radius=1.5", true)]
+    #[case::sharpen_radius_zero("?sharpen-radius=0", false)]
+    #[case::sharpen_radius_out_of_range("?sharpen-radius=11", false)]
+    #[case::sharpen_amount("?sharpen-amount=0.5", true)]
+    #[case::sharpen_amount_out_of_range("?sharpen-amount=-1", false)]
+    #[case::trim_threshold("?trim=auto&trim-threshold=10.5", true)]
+    #[case::trim_threshold_out_of_range("?trim=auto&trim-threshold=256", false)]
+    #[case::trim_lineart("?trim=auto&trim-lineart=true", true)]
+    #[case::kodachrome("?kodachrome=50", true)]
+    #[case::kodachrome_zero("?kodachrome=0", false)]
+    #[case::kodachrome_out_of_range("?kodachrome=101", false)]
+    #[case::dpr_fractional("?dpr=1.5", true)]
+    #[case::dpr_infinite("?dpr=inf", false)]
+    #[case::dpr_nan("?dpr=NaN", false)]
+    #[case::encoder("?encoder=svt", true)]
+    #[case::encoder_unknown("?encoder=x265", false)]
+    #[case::bitdepth("?bitdepth=10", true)]
+    #[case::bitdepth_invalid("?bitdepth=9", false)]
+    #[case::blurhash("?fm=blurhash&bh-x=9&bh-y=1", true)]
+    #[case::blurhash_components_out_of_range("?bh-x=0", false)]
+    #[case::colour("?fm=colour", true)]
+    #[case::color("?fm=color", true)]
+    #[case::greyscale("?greyscale=true", true)]
+    #[case::grayscale("?grayscale=true", true)]
+    #[case::posterize("?posterize=2", true)]
+    #[case::posterize_out_of_range("?posterize=257", false)]
+    #[case::meta("?meta=1", true)]
+    #[case::meta_false("?meta=false", true)]
+    #[case::meta_invalid("?meta=yes", false)]
+    #[case::meta_format("?fm=meta", false)]
+    fn test_option_validation(#[case] query: &str, #[case] valid: bool) {
+        assert_eq!(parse_query(query).is_some(), valid);
+    }
+
+    #[rstest]
+    #[case::centre(Gravity::Centre, (50, 25))]
+    #[case::north(Gravity::North, (50, 0))]
+    #[case::south(Gravity::South, (50, 50))]
+    #[case::east(Gravity::East, (100, 25))]
+    #[case::west(Gravity::West, (0, 25))]
+    #[case::northeast(Gravity::NorthEast, (100, 0))]
+    #[case::southwest(Gravity::SouthWest, (0, 50))]
+    fn test_gravity_offsets(#[case] gravity: Gravity, #[case] expected: (i32, i32)) {
+        assert_eq!(gravity.offsets((200, 100), (100, 50)), expected);
+    }
+
+    #[rstest]
+    #[case::pixels("?radius=20", Some(Radius::Pixels(20)))]
+    #[case::percent("?radius=10%25", Some(Radius::Percent(10)))]
+    #[case::percent_out_of_range("?radius=51%25", None)]
+    #[case::invalid("?radius=abc", None)]
+    fn test_radius(#[case] query: &str, #[case] expected: Option<Radius>) {
+        assert_eq!(
+            parse_query(query).and_then(|options| options.radius),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::pad("?w=100&h=100&fit=pad", Some((100, 100)))]
+    #[case::pad_dpr("?w=100&h=50&fit=pad&dpr=2", Some((200, 100)))]
+    #[case::pad_dpr_fractional("?w=101&h=50&fit=pad&dpr=1.5", Some((152, 75)))]
+    #[case::pad_width_only("?w=100&fit=pad", None)]
+    #[case::clip("?w=100&h=100&fit=clip", None)]
+    fn test_pad_dimensions(#[case] query: &str, #[case] expected: Option<(i32, i32)>) {
+        assert_eq!(parse_query(query).unwrap().pad_dimensions(), expected);
+    }
+
+    #[rstest]
+    #[case::none("?fit=crop", (None, None))]
+    #[case::width("?w=100", (Some(100), None))]
+    #[case::dpr("?w=100&h=50&dpr=2", (Some(200), Some(100)))]
+    #[case::aspect_ratio_width("?w=400&ar=4:3", (Some(400), Some(300)))]
+    #[case::aspect_ratio_height("?h=300&ar=4:3&dpr=2", (Some(800), Some(600)))]
+    fn test_requested_dimensions(
+        #[case] query: &str,
+        #[case] expected: (Option<i64>, Option<i64>),
+    ) {
+        assert_eq!(parse_query(query).unwrap().requested_dimensions(), expected);
+    }
+
+    #[rstest]
+    #[case::none("?fit=crop", (None, None))]
+    #[case::width("?w=100", (Some(100), Some(25)))]
+    #[case::height("?h=100&dpr=2", (Some(800), Some(200)))]
+    #[case::aspect_ratio("?w=400&ar=4:3", (Some(400), Some(300)))]
+    fn test_output_dimensions(#[case] query: &str, #[case] expected: (Option<i64>, Option<i64>)) {
+        assert_eq!(
+            parse_query(query).unwrap().output_dimensions((4000, 1000)),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::smart("?crop=smart", Some(Crop::Smart))]
+    #[case::entropy("?crop=entropy", Some(Crop::Entropy))]
+    #[case::rect("?crop=10,20,300,200", Some(Crop::Rect(CropRect { x: 10, y: 20, width: 300, height: 200 })))]
+    #[case::zero_area("?crop=10,20,0,200", None)]
+    fn test_crop(#[case] query: &str, #[case] expected: Option<Crop>) {
+        let options = parse_query(query).unwrap();
+        assert_eq!(options.crop, expected);
+        if let Some(crop) = expected {
+            assert_eq!(options.query_str(), format!("crop={crop}"));
+        }
+    }
+
+    #[rstest]
+    #[case::percentage("?sharpen=50", true)]
+    #[case::off("?sharpen=0&sharpen-radius=2", false)]
+    #[case::radius("?sharpen-radius=2", true)]
+    #[case::amount("?sharpen-amount=2", true)]
+    #[case::unset("?w=100", false)]
+    fn test_sharpens(#[case] query: &str, #[case] expected: bool) {
+        assert_eq!(parse_query(query).unwrap().sharpens(), expected);
+    }
+
+    #[test]
+    fn test_with_preset() {
+        let preset = BTreeMap::from([
+            ("w".to_string(), "150".to_string()),
+            ("h".to_string(), "150".to_string()),
+            ("fit".to_string(), "crop".to_string()),
+        ]);
+        let options = with_preset("preset=thumb&w=300&q=70", &preset).unwrap();
+        assert_eq!(options.width, Some(300));
+        assert_eq!(options.height, Some(150));
+        assert_eq!(options.fit, Some(Fit::Crop));
+        assert_eq!(options.quality, Some(70));
+        assert_eq!(options.preset.as_deref(), Some("thumb"));
+
+        let invalid = BTreeMap::from([("fit".to_string(), "sideways".to_string())]);
+        assert!(with_preset("", &invalid).is_err());
+    }
+
+    #[rstest]
+    #[case::resize("?fm=gif&w=100", true)]
+    #[case::radius("?fm=gif&radius=10", false)]
+    #[case::vignette("?fm=gif&vignette=50", false)]
+    #[case::pixelate("?fm=gif&pixelate=8", false)]
+    fn test_preserves_animation(#[case] query: &str, #[case] expected: bool) {
+        assert_eq!(parse_query(query).unwrap().preserves_animation(), expected);
+    }
+
+    #[rstest]
+    #[case::over("over", BlendMode::Over)]
+    #[case::soft_light("soft-light", BlendMode::SoftLight)]
+    #[case::colour_dodge("colour-dodge", BlendMode::ColourDodge)]
+    fn test_overlay_blend(#[case] value: &str, #[case] expected: BlendMode) {
+        let query = format!(
+            "?overlay=https://example.com/badge.png&overlay-x=10&overlay-y=-5&overlay-blend={value}"
+        );
+        let options = parse_query(&query).unwrap();
+        assert_eq!(options.overlay_blend, Some(expected));
+        assert_eq!(
+            options.query_str(),
+            format!(
+                "overlay=https://example.com/badge.png&overlay-blend={value}&overlay-x=10&overlay-y=-5"
+            )
+        );
+        assert!(!options.preserves_animation());
+        let options = parse_query("?overlay-blend=color-dodge").unwrap();
+        assert_eq!(options.overlay_blend, Some(BlendMode::ColourDodge));
+    }
+
+    #[rstest]
+    #[case::nearest("nearest", Kernel::Nearest)]
+    #[case::linear("linear", Kernel::Linear)]
+    #[case::cubic("cubic", Kernel::Cubic)]
+    #[case::lanczos2("lanczos2", Kernel::Lanczos2)]
+    #[case::lanczos3("lanczos3", Kernel::Lanczos3)]
+    fn test_kernel(#[case] value: &str, #[case] expected: Kernel) {
+        let options = parse_query(&format!("?kernel={value}")).unwrap();
+        assert_eq!(options.kernel, Some(expected));
+        assert!(options.any_set());
+        assert_eq!(options.query_str(), format!("kernel={value}"));
+        assert!(parse_query("?kernel=mitchell").is_none());
+    }
+
+    #[rstest]
+    #[case::unknown("?crop=attention")]
+    #[case::too_few("?crop=10,20,300")]
+    #[case::not_a_number("?crop=10,20,300,abc")]
+    #[case::negative("?crop=0,0,-10,10")]
+    fn test_crop_invalid(#[case] query: &str) {
+        assert!(parse_query(query).is_none());
+    }
+
+    #[rstest]
+    #[case::inside((10, 20, 300, 200), Some((10, 20, 300, 200)))]
+    #[case::overflow((500, 300, 300, 200), Some((500, 300, 100, 100)))]
+    #[case::negative_origin((-50, -50, 100, 100), Some((0, 0, 50, 50)))]
+    #[case::outside((700, 0, 100, 100), None)]
+    fn test_crop_rect_clamp(
+        #[case] rect: (i32, i32, i32, i32),
+        #[case] expected: Option<(i32, i32, i32, i32)>,
+    ) {
+        let (x, y, width, height) = rect;
+        let clamped = CropRect {
+            x,
+            y,
+            width,
+            height,
+        }
+        .clamp(600, 400);
+        assert_eq!(
+            clamped.map(|rect| (rect.x, rect.y, rect.width, rect.height)),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::unset("?w=100", None)]
+    #[case::within("?dpr=2", Some(2.0))]
+    #[case::fractional("?dpr=1.5", Some(1.5))]
+    #[case::above("?dpr=50", Some(3.0))]
+    #[case::zero("?dpr=0", Some(1.0))]
+    #[case::negative("?dpr=-2", Some(1.0))]
+    fn test_clamp_device_pixel_ratio(#[case] query: &str, #[case] expected: Option<f64>) {
+        let mut options = parse_query(query).unwrap();
+        options.clamp_device_pixel_ratio(3.0);
+        assert_eq!(options.device_pixel_ratio, expected);
+    }
+
+    #[rstest]
+    #[case::colour("colour", ImageFormat::Colour)]
+    #[case::color("color", ImageFormat::Colour)]
+    #[case::blurhash("blurhash", ImageFormat::Blurhash)]
+    fn test_image_format_names(#[case] name: &str, #[case] expected: ImageFormat) {
+        let format = ImageFormat::from_str(name).unwrap();
+        assert_eq!(format, expected);
+        // Cached responses store the canonical name
+        assert_eq!(
+            ImageFormat::from_str(&format.to_string()).unwrap(),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::default("?w=100", ops::ForeignKeep::Icc)]
+    #[case::strip("?strip=true", ops::ForeignKeep::Icc)]
+    #[case::keep("?strip=false", ops::ForeignKeep::All)]
+    fn test_keep_metadata(#[case] query: &str, #[case] expected: ops::ForeignKeep) {
+        assert_eq!(
+            parse_query(query).unwrap().keep_metadata() as i32,
+            expected as i32
+        );
+    }
+
     fn get_image_options() -> ImageOptions {
         ImageOptions {
             width: Some(300),
             height: Some(200),
             quality: Some(80),
             aspect_ratio: Some(AspectRatio::new(16, 9)),
-            device_pixel_ratio: Some(2),
+            device_pixel_ratio: Some(2.0),
             fit: Some(Fit::Crop),
             background: Some(Colour { r: 255, g: 0, b: 0 }),
             format: Some(ImageFormat::Jpeg),
@@ -818,11 +2556,62 @@ mod tests {
     #[test]
     fn test_signing() {
         let secret = "super_secret_key";
-        let signature = get_image_options().sign(secret);
+        let options = get_image_options();
+        let signature = options.sign(secret, "/samples/08.jpg", SigningAlgorithm::Sha256);
+
+        assert_eq!(
+            signature,
+            "632844279c8a97cd15baa9912a9c815fc24d35407e39313af221627cc38ebc0f"
+        );
+
+        // The signature doesn't carry over to another image
+        let options = ImageOptions {
+            signature: Some(signature),
+            ..options
+        };
+        assert!(options.verify_signature(secret, "/samples/08.jpg", SigningAlgorithm::Sha256));
+        assert!(!options.verify_signature(secret, "/samples/09.jpg", SigningAlgorithm::Sha256));
+    }
+
+    #[test]
+    fn test_signing_sha512() {
+        let secret = "super_secret_key";
+        let signature =
+            get_image_options().sign(secret, "/samples/08.jpg", SigningAlgorithm::Sha512);
 
         assert_eq!(
             signature,
-            "210868675de768f0320ad506c85580bf686ab2feec6a35542e93c378e078e28a"
+            "e8e9be0ce228b4ae7c9da7a856b8a2d014a0195555c7104026f0b4572df7cb61abf89870fec415c3ed7b213bf798491e2cc52ef1bb9991a1974b1c3e00c9d4ad"
+        );
+
+        // The algorithm has to match the configuration
+        let options = ImageOptions {
+            signature: Some(signature),
+            ..get_image_options()
+        };
+        assert!(options.verify_signature(secret, "/samples/08.jpg", SigningAlgorithm::Sha512));
+        assert!(!options.verify_signature(secret, "/samples/08.jpg", SigningAlgorithm::Sha256));
+    }
+
+    #[rstest]
+    #[case::not_expired(1_700_000_000, 1_699_999_999, true)]
+    #[case::expiry_second(1_700_000_000, 1_700_000_000, true)]
+    #[case::expired(1_700_000_000, 1_700_000_001, false)]
+    fn test_signature_expiry(#[case] expires: i64, #[case] now: i64, #[case] valid: bool) {
+        let secret = "super_secret_key";
+        let mut options = ImageOptions {
+            expires: Some(expires),
+            ..get_image_options()
+        };
+        let algorithm = SigningAlgorithm::Sha256;
+        options.signature = Some(options.sign(secret, "/image.jpg", algorithm));
+        assert_eq!(
+            options.verify_signature_at(secret, "/image.jpg", algorithm, now),
+            valid
         );
+
+        // The expiry is covered by the signature, so it can't be extended
+        options.expires = Some(expires + 3600);
+        assert!(!options.verify_signature_at(secret, "/image.jpg", algorithm, now));
     }
 }
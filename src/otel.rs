@@ -1,7 +1,10 @@
-use crate::config::Config;
-use opentelemetry_otlp::SpanExporter;
+use crate::config::{Config, LogFormat};
+use opentelemetry::global;
 use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_otlp::{MetricExporter, SpanExporter};
 use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
 use opentelemetry_sdk::trace::SdkTracerProvider;
 use std::sync::OnceLock;
 use tracing_subscriber::EnvFilter;
@@ -13,20 +16,26 @@ fn get_resource() -> Resource {
         .clone()
 }
 
-pub fn setup_logging() {
+pub fn setup_logging(format: LogFormat) {
     let filter = EnvFilter::from_default_env()
         .add_directive("hyper=off".parse().unwrap())
         .add_directive("tonic=off".parse().unwrap())
         .add_directive("h2=off".parse().unwrap())
         .add_directive("opentelemetry=info".parse().unwrap())
         .add_directive("reqwest=off".parse().unwrap());
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .with_env_filter(filter)
-        .with_thread_names(true)
-        .init();
+        .with_thread_names(true);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
 }
 
 pub fn setup_tracing(config: &Config) -> SdkTracerProvider {
+    // Continue traces started by callers that send W3C `traceparent` headers
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
     let mut exporter = SpanExporter::builder()
         .with_tonic()
         .with_timeout(std::time::Duration::from_secs(5));
@@ -51,3 +60,27 @@ pub fn setup_tracing(config: &Config) -> SdkTracerProvider {
 
     provider.build()
 }
+
+/// Export metrics to the OTel collector alongside the Prometheus endpoint. Like
+/// tracing, nothing is exported when no collector endpoint is configured.
+pub fn setup_metrics_otel(config: &Config) -> Option<SdkMeterProvider> {
+    let Some(endpoint) = &config.otel_collector_endpoint else {
+        tracing::warn!("OTel collector endpoint is not set, metrics will not be exported");
+        return None;
+    };
+
+    let exporter = MetricExporter::builder()
+        .with_tonic()
+        .with_timeout(std::time::Duration::from_secs(5))
+        .with_protocol(opentelemetry_otlp::Protocol::Grpc)
+        .with_endpoint(endpoint)
+        .build()
+        .expect("failed to create metric exporter");
+
+    let provider = SdkMeterProvider::builder()
+        .with_resource(get_resource())
+        .with_periodic_exporter(exporter)
+        .build();
+    global::set_meter_provider(provider.clone());
+    Some(provider)
+}
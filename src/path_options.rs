@@ -0,0 +1,121 @@
+//! Transformations given as a path segment, e.g. `/img/w_300,h_200,fit_crop/cat.jpg`,
+//! for CDNs that don't cache on the query string. Each comma separated
+//! `name_value` pair uses the query parameter's name.
+
+use crate::options;
+use std::collections::BTreeMap;
+
+/// Split the transformation segment off the front of a captured request path.
+/// A path is left alone unless its first segment contains `_` and is followed
+/// by more of the path, so plain image paths keep working.
+pub fn split(request_path: &str) -> Option<(&str, &str)> {
+    let (segment, rest) = request_path.split_once('/')?;
+    (segment.contains('_') && !rest.is_empty()).then_some((segment, rest))
+}
+
+/// Parse a transformation segment into query parameters.
+pub fn parse(segment: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut params = BTreeMap::new();
+    for token in segment.split(',') {
+        let Some((name, value)) = token
+            .split_once('_')
+            .filter(|(name, value)| !name.is_empty() && !value.is_empty())
+        else {
+            return Err(format!("invalid transformation `{token}`"));
+        };
+        if params.insert(name.to_string(), value.to_string()).is_some() {
+            return Err(format!("transformation `{name}` is set more than once"));
+        }
+    }
+    Ok(params)
+}
+
+/// Move the transformation segment of a request into its query string, so it's
+/// handled and signed like the equivalent query string URL. `prefix` is the
+/// route's literal path before the capture, including the leading slash.
+///
+/// Returns the remaining captured path and the rewritten path and query, or
+/// `None` when the path has no transformation segment.
+pub fn rewrite(
+    request_path: &str,
+    uri_path: &str,
+    query: &str,
+    prefix: &str,
+) -> Result<Option<(String, String)>, String> {
+    let Some((segment, rest)) = split(request_path) else {
+        return Ok(None);
+    };
+    let params = parse(segment)?;
+    // The URI path is still percent-encoded, so drop its first captured segment
+    // rather than reusing the decoded one
+    let encoded_rest = uri_path
+        .strip_prefix(prefix)
+        .and_then(|captured| captured.split_once('/'))
+        .map_or(rest, |(_, rest)| rest);
+    let query = options::merge_query(query, &params);
+    Ok(Some((
+        rest.to_string(),
+        format!("{prefix}{encoded_rest}?{query}"),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::transformations("w_300,h_200/photos/cat.jpg", Some(("w_300,h_200", "photos/cat.jpg")))]
+    #[case::no_underscore("photos/cat.jpg", None)]
+    #[case::file_only("w_300", None)]
+    #[case::file_with_underscore("my_cat.jpg", None)]
+    #[case::trailing_slash("w_300/", None)]
+    fn test_split(#[case] path: &str, #[case] expected: Option<(&str, &str)>) {
+        assert_eq!(split(path), expected);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("w_300,h_200,fit_crop,trim-colour_ff_00").unwrap(),
+            BTreeMap::from([
+                ("fit".to_string(), "crop".to_string()),
+                ("h".to_string(), "200".to_string()),
+                ("trim-colour".to_string(), "ff_00".to_string()),
+                ("w".to_string(), "300".to_string()),
+            ])
+        );
+    }
+
+    #[rstest]
+    #[case::missing_value("w_")]
+    #[case::missing_name("_300")]
+    #[case::no_separator("w300")]
+    #[case::empty_token("w_300,,h_200")]
+    #[case::trailing_comma("w_300,")]
+    #[case::duplicate("w_300,w_400")]
+    fn test_parse_malformed(#[case] segment: &str) {
+        assert!(parse(segment).is_err());
+    }
+
+    #[test]
+    fn test_rewrite() {
+        assert_eq!(
+            rewrite(
+                "w_300,fit_crop/my photos/cat.jpg",
+                "/img/w_300,fit_crop/my%20photos/cat.jpg",
+                "w=400&q=70",
+                "/img/",
+            ),
+            Ok(Some((
+                "my photos/cat.jpg".to_string(),
+                "/img/my%20photos/cat.jpg?fit=crop&w=400&q=70".to_string()
+            )))
+        );
+        assert_eq!(
+            rewrite("photos/cat.jpg", "/img/photos/cat.jpg", "", "/img/"),
+            Ok(None)
+        );
+        assert!(rewrite("w_/cat.jpg", "/img/w_/cat.jpg", "", "/img/").is_err());
+    }
+}
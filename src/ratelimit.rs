@@ -0,0 +1,159 @@
+use crate::config::RateLimitConfig;
+use crate::http::HeaderMapExt;
+use crate::service::Service;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::{middleware::Next, response::IntoResponse, response::Response};
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+const SHARDS: usize = 16;
+
+/// Idle buckets are pruned once a shard holds this many clients.
+const MAX_SHARD_ENTRIES: usize = 4096;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter keyed by client, sharded to reduce lock contention.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    hasher: RandomState,
+    shards: Vec<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            rate: config.requests_per_second,
+            burst: f64::from(config.burst.max(1)),
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    /// Take a token for the client, returning false when it is over the limit.
+    pub fn check(&self, key: &str) -> bool {
+        self.check_at(key, Instant::now())
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn check_at(&self, key: &str, now: Instant) -> bool {
+        let shard = self.hasher.hash_one(key) as usize % SHARDS;
+        let mut buckets = self.shards[shard]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if buckets.len() >= MAX_SHARD_ENTRIES && !buckets.contains_key(key) {
+            // Forget clients whose buckets have refilled, as they're equivalent to new ones
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// The key a client is limited by. `X-Forwarded-For` is only read when the
+/// connection comes from a trusted proxy, otherwise clients could pick their
+/// own key by sending the header.
+fn client_key(peer: Option<IpAddr>, headers: &HeaderMap, proxies: &[IpNet]) -> String {
+    match peer {
+        Some(peer) if proxies.iter().any(|proxy| proxy.contains(&peer)) => headers
+            .get_x_forwarded_for(proxies)
+            .unwrap_or_else(|| peer.to_string()),
+        Some(peer) => peer.to_string(),
+        None => String::new(),
+    }
+}
+
+pub async fn middleware(State(ctx): State<Arc<Service>>, req: Request, next: Next) -> Response {
+    let Some(limiter) = &ctx.rate_limiter else {
+        return next.run(req).await;
+    };
+
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let client = client_key(peer, req.headers(), &ctx.config().proxies);
+
+    if !limiter.check(&client) {
+        metrics::counter!("shrinkray_http_response_429").increment(1);
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use std::time::Duration;
+
+    #[rstest]
+    #[case::untrusted_peer("203.0.113.9", "198.51.100.1", "203.0.113.9")]
+    #[case::trusted_proxy("10.0.0.2", "198.51.100.1", "198.51.100.1")]
+    #[case::spoofed_through_proxy("10.0.0.2", "198.51.100.1, 10.0.0.3", "198.51.100.1")]
+    fn test_client_key(#[case] peer: &str, #[case] forwarded: &str, #[case] expected: &str) {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", forwarded.parse().unwrap());
+        let proxies = vec!["10.0.0.0/8".parse().unwrap()];
+        assert_eq!(
+            client_key(Some(peer.parse().unwrap()), &headers, &proxies),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_client_key_without_proxies() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "198.51.100.1".parse().unwrap());
+        let peer = "203.0.113.9".parse().unwrap();
+        assert_eq!(client_key(Some(peer), &headers, &[]), "203.0.113.9");
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            requests_per_second: 2.0,
+            burst: 3,
+        });
+        let start = Instant::now();
+
+        // The burst is available immediately
+        assert!(limiter.check_at("a", start));
+        assert!(limiter.check_at("a", start));
+        assert!(limiter.check_at("a", start));
+        assert!(!limiter.check_at("a", start));
+
+        // Other clients have their own bucket
+        assert!(limiter.check_at("b", start));
+
+        // Tokens refill at the configured rate
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check_at("a", later));
+        assert!(!limiter.check_at("a", later));
+    }
+}
@@ -0,0 +1,78 @@
+use axum::http::{HeaderName, HeaderValue};
+use axum::{extract::Request, middleware::Next, response::Response};
+use ring::rand::{SecureRandom, SystemRandom};
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Incoming IDs longer than this are replaced rather than logged.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Give every request an ID, keeping one sent by the client or an upstream
+/// proxy. The ID is set on the request headers for the handlers and access log,
+/// and echoed back in the response.
+pub async fn middleware(mut req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(&X_REQUEST_ID)
+        .filter(|value| is_valid(value))
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_str(&generate()).expect("uuid is a valid header"));
+    req.headers_mut().insert(X_REQUEST_ID.clone(), id.clone());
+
+    let mut response = next.run(req).await;
+    response.headers_mut().insert(X_REQUEST_ID.clone(), id);
+    response
+}
+
+fn is_valid(value: &HeaderValue) -> bool {
+    let bytes = value.as_bytes();
+    !bytes.is_empty() && bytes.len() <= MAX_REQUEST_ID_LEN && bytes.iter().all(u8::is_ascii_graphic)
+}
+
+/// Generate a random version 4 UUID.
+fn generate() -> String {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("failed to generate random bytes");
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[test]
+    fn test_generate() {
+        let id = generate();
+        let groups: Vec<&str> = id.split('-').collect();
+        assert_eq!(
+            groups.iter().map(|group| group.len()).collect::<Vec<_>>(),
+            [8, 4, 4, 4, 12]
+        );
+        assert!(groups[2].starts_with('4'));
+        assert!(groups[3].starts_with(['8', '9', 'a', 'b']));
+        assert_ne!(id, generate());
+    }
+
+    #[rstest]
+    #[case("abc-123", true)]
+    #[case("", false)]
+    #[case("has space", false)]
+    #[case(&"a".repeat(129), false)]
+    fn test_is_valid(#[case] value: &str, #[case] expected: bool) {
+        assert_eq!(is_valid(&HeaderValue::from_str(value).unwrap()), expected);
+    }
+}
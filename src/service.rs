@@ -1,21 +1,114 @@
+use crate::backend::{BackendFile, get_file_from_backend, http_client};
+use crate::cache::{Caches, DiskCache, LruCache, RedisCache};
+use crate::circuit::CircuitBreaker;
 use crate::config::Config;
-use crate::error::Error;
+use crate::error::{Error, Result};
+use crate::ratelimit::RateLimiter;
 use libvips::{VipsApp, error::Error as VipsError};
 use once_cell::sync::OnceCell;
+use reqwest::header::HeaderMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
 use tokio::signal;
+use tokio::sync::Semaphore;
 
 pub struct Service {
     pub vips_app: &'static VipsApp,
-    pub config: Config,
+    config: RwLock<Arc<Config>>,
+    pub client: reqwest::Client,
+    pub cache: Caches,
+    pub rate_limiter: Option<RateLimiter>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Limits how many images are processed at once, if configured
+    pub processing: Option<Arc<Semaphore>>,
+    ready: AtomicBool,
 }
 
 impl Service {
     pub fn new(config: Config) -> Self {
+        let mut cache = Caches::default();
+        if let Some(max_bytes) = config.lru_max_bytes {
+            cache.push(LruCache::new(max_bytes));
+        }
+        if let Some(dir) = &config.cache_dir {
+            cache.push(DiskCache::new(dir));
+        }
+        if let Some(redis) = &config.redis {
+            match RedisCache::new(redis) {
+                Ok(redis) => cache.push(redis),
+                Err(err) => tracing::warn!(error = %err, "invalid redis url, cache disabled"),
+            }
+        }
+
         Self {
             vips_app: create_vips_app(),
-            config,
+            rate_limiter: config.rate_limit.as_ref().map(RateLimiter::new),
+            circuit_breaker: config.circuit_breaker.as_ref().map(CircuitBreaker::new),
+            processing: config
+                .max_concurrent_processing
+                .map(|permits| Arc::new(Semaphore::new(permits.max(1)))),
+            client: http_client(&config).expect("failed to build http client"),
+            config: RwLock::new(Arc::new(config)),
+            cache,
+            ready: AtomicBool::new(false),
         }
     }
+
+    /// Whether the service should receive traffic, reported by `/readyz`.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Mark the service as ready once it's listening, or not ready when it's
+    /// shutting down so load balancers stop routing to it before it drains.
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    /// The current configuration. Requests should take this once so a reload
+    /// part way through doesn't mix old and new values.
+    pub fn config(&self) -> Arc<Config> {
+        self.config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Replace the configuration after a reload. Settings used to build the
+    /// service itself, such as listen addresses and caches, require a restart.
+    pub fn set_config(&self, config: Config) {
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+    }
+
+    /// Fetch a file from a backend, failing fast while the host's circuit is open.
+    pub async fn fetch(
+        &self,
+        url: &str,
+        config: &Config,
+        headers: HeaderMap,
+    ) -> Result<BackendFile> {
+        // Only configured endpoints get a circuit, so hosts given in requests
+        // can't grow the map or the per-host metrics
+        let host = url::Url::parse(url)?
+            .host_str()
+            .filter(|host| config.is_endpoint_host(host))
+            .map(str::to_string);
+        let (Some(breaker), Some(host)) = (&self.circuit_breaker, host) else {
+            return get_file_from_backend(url, &self.client, config, headers).await;
+        };
+        if !breaker.allow(&host) {
+            return Err(Error::CircuitOpen);
+        }
+        let result = get_file_from_backend(url, &self.client, config, headers).await;
+        // Only failures to reach the backend and server errors that outlasted the
+        // retries count, not missing files or bad input
+        breaker.record(
+            &host,
+            !matches!(result, Err(Error::Http(_) | Error::Upstream(500..=599))),
+        );
+        result
+    }
+
     pub fn vips_error(&self, err: VipsError) -> Error {
         let error_buffer = self.vips_app.error_buffer().unwrap_or("").replace('\n', "");
         self.vips_app.error_clear();
@@ -23,7 +116,7 @@ impl Service {
     }
 }
 
-fn create_vips_app() -> &'static VipsApp {
+pub(crate) fn create_vips_app() -> &'static VipsApp {
     // libvips requires global initialization and assumes there is only
     // one global VipsApp per process. Creating multiple instances of
     // VipsApp::new(...) in the same test binary (even across different
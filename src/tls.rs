@@ -0,0 +1,197 @@
+//! HTTPS for the image server, accepting TLS connections for `axum::serve` so
+//! graceful shutdown works the same as with plain HTTP.
+
+use axum::serve::Listener;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::server::TlsStream;
+use tracing::debug;
+
+use crate::config::TlsConfig;
+
+/// How long a client has to complete the handshake before it's dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Build a TLS acceptor from the configured certificate chain and private key.
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, Box<dyn std::error::Error>> {
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(std::fs::File::open(&config.cert)?))
+        .collect::<Result<Vec<CertificateDer>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in `{}`", config.cert).into());
+    }
+    let key: PrivateKeyDer =
+        rustls_pemfile::private_key(&mut io::BufReader::new(std::fs::File::open(&config.key)?))?
+            .ok_or_else(|| format!("no private key found in `{}`", config.key))?;
+
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// A listener that completes TLS handshakes before handing connections to the
+/// server. Handshakes run concurrently, so a slow client doesn't hold up others.
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    handshakes: JoinSet<Option<(TlsStream<TcpStream>, SocketAddr)>>,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor) -> Self {
+        Self {
+            listener,
+            acceptor,
+            handshakes: JoinSet::new(),
+        }
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            tokio::select! {
+                (stream, addr) = Listener::accept(&mut self.listener) => {
+                    let acceptor = self.acceptor.clone();
+                    self.handshakes.spawn(async move {
+                        match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => Some((stream, addr)),
+                            Ok(Err(err)) => {
+                                debug!("tls handshake with {} failed: {}", addr, err);
+                                None
+                            }
+                            Err(_) => {
+                                debug!("tls handshake with {} timed out", addr);
+                                None
+                            }
+                        }
+                    });
+                }
+                // A failed handshake mustn't disable this branch, or later
+                // connections would wait for the next TCP accept
+                Some(result) = self.handshakes.join_next() => {
+                    if let Ok(Some(connection)) = result {
+                        return connection;
+                    }
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, pki_types::ServerName};
+
+    fn fixture_config() -> TlsConfig {
+        TlsConfig {
+            cert: "tests/fixtures/tls-cert.pem".to_string(),
+            key: "tests/fixtures/tls-key.pem".to_string(),
+        }
+    }
+
+    /// A client that trusts the fixture certificate
+    fn client_config() -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        let cert = std::fs::read("tests/fixtures/tls-cert.pem").unwrap();
+        for cert in rustls_pemfile::certs(&mut cert.as_slice()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        Arc::new(
+            ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    }
+
+    #[test]
+    fn test_acceptor_invalid_files() {
+        let missing = TlsConfig {
+            key: "tests/fixtures/missing.pem".to_string(),
+            ..fixture_config()
+        };
+        assert!(acceptor(&missing).is_err());
+        let swapped = TlsConfig {
+            cert: "tests/fixtures/tls-key.pem".to_string(),
+            key: "tests/fixtures/tls-cert.pem".to_string(),
+        };
+        assert!(acceptor(&swapped).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tls_listener_after_failed_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut listener = TlsListener::new(listener, acceptor(&fixture_config()).unwrap());
+
+        // A probe fails its handshake after a real client has connected but
+        // before that client's handshake completes
+        let client = tokio::spawn(async move {
+            let probe = TcpStream::connect(addr).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let stream = TcpStream::connect(addr).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(probe);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut stream = TlsConnector::from(client_config())
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+                .unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let (mut stream, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .expect("connection stalled after a failed handshake");
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"ping");
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut listener = TlsListener::new(listener, acceptor(&fixture_config()).unwrap());
+
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut stream = TlsConnector::from(client_config())
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+                .unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let (mut stream, _) = listener.accept().await;
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"ping");
+        client.await.unwrap();
+    }
+}